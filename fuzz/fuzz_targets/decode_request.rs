@@ -0,0 +1,19 @@
+// Feed arbitrary input through the request decoding of every receiver and REST route
+// Run with: cargo +nightly fuzz run decode_request
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vradio_ws::fuzzing;
+
+fuzz_target!(|data: &[u8]| {
+    // Text frames are always valid utf-8, warp drops anything else before it reaches the parser
+    let message = match std::str::from_utf8(data) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    // Frames name their receiver, anything else is tried as a REST body
+    if fuzzing::decode_frame(message).is_none() {
+        fuzzing::decode_bodies(message);
+    }
+});
@@ -0,0 +1,16 @@
+// Feed arbitrary text frames through the parser and validators used by the websocket
+// Run with: cargo +nightly fuzz run parse_frame
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vradio_ws::fuzzing;
+
+fuzz_target!(|data: &[u8]| {
+    // Text frames are always valid utf-8, warp drops anything else before it reaches the parser
+    let message = match std::str::from_utf8(data) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    fuzzing::parse_frame(message);
+});
@@ -0,0 +1,178 @@
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use redis::aio::Connection;
+use serde::Deserialize;
+use uuid::Uuid;
+use crate::DirectError::RedisCMDError;
+use crate::redis_direct::Result as RedisResult;
+use crate::station::{Role, Station, Visibility};
+
+// Maximum amount of characters in a station password
+pub const MAX_PASSWORD_LEN: usize = 128;
+// Header REST requests send the password of a password protected station in
+pub const PASSWORD_HEADER: &str = "x-vradio-password";
+// Wrong passwords a user may send for a station before they have to wait
+const MAX_PASSWORD_FAILURES: u64 = 5;
+// Seconds the wrong passwords of a user are counted for, counted again from the last wrong one
+const PASSWORD_FAILURE_WINDOW_SECS: u64 = 300;
+
+// Join request sent as json, needed when the station asks for a password
+#[derive(Deserialize, Debug)]
+pub struct JoinRequest {
+    #[serde(rename = "code")]
+    pub code: String,
+    #[serde(rename = "password")]
+    pub password: Option<String>,
+}
+
+impl JoinRequest {
+    // Read a join request, a bare join code is still accepted
+    pub fn parse(msg: &str) -> Option<JoinRequest> {
+        let msg = msg.trim_end_matches('\n');
+        if !msg.starts_with('{') {
+            return Some(JoinRequest { code: msg.to_string(), password: None });
+        }
+
+        serde_json::from_str(msg).ok()
+    }
+}
+
+// Reason a client may not join a station
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    Banned,
+    PasswordRequired,
+    WrongPassword,
+    TooManyAttempts,
+    NotInvited,
+}
+
+impl Denied {
+    // Error code sent to the client
+    pub fn code(self) -> &'static str {
+        match self {
+            Denied::Banned => "banned",
+            Denied::PasswordRequired => "password_required",
+            Denied::WrongPassword => "wrong_password",
+            Denied::TooManyAttempts => "too_many_attempts",
+            Denied::NotInvited => "not_invited",
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            Denied::Banned => "you are banned from this station",
+            Denied::PasswordRequired => "this station needs a password",
+            Denied::WrongPassword => "the password is not correct",
+            Denied::TooManyAttempts => "too many wrong passwords, try again later",
+            Denied::NotInvited => "this station is invite only",
+        }
+    }
+}
+
+// Hash a station password, the salt comes from a random uuid
+// Runs on the blocking pool like verify_password, it is reachable from REST and websocket frames alike
+pub async fn hash_password(password: &str) -> Option<String> {
+    let password = password.to_string();
+
+    let hashed = tokio::task::spawn_blocking(move || {
+        let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes()).ok()?;
+
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|h| h.to_string())
+            .ok()
+    }).await;
+
+    hashed.ok().flatten()
+}
+
+// Check a password against a stored hash
+// Argon2 is slow on purpose, so it runs on the blocking pool instead of holding up other connections
+async fn verify_password(hash: &str, password: &str) -> bool {
+    let (hash, password) = (hash.to_string(), password.to_string());
+
+    let verified = tokio::task::spawn_blocking(move || match PasswordHash::new(&hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => {
+            eprintln!("invalid station password hash");
+            false
+        }
+    }).await;
+
+    verified.unwrap_or(false)
+}
+
+// Construct the redis key counting the wrong passwords a user sent for a station
+fn failures_key(station_id: Uuid, user_id: usize) -> String {
+    format!("join-failures:{}:{}", station_id, user_id)
+}
+
+// Get how many wrong passwords a user sent for a station recently
+async fn failures(con: &mut Connection, station_id: Uuid, user_id: usize) -> RedisResult<u64> {
+    let failures: Option<u64> = redis::cmd("GET")
+        .arg(failures_key(station_id, user_id))
+        .query_async(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    Ok(failures.unwrap_or(0))
+}
+
+// Count a wrong password, the count is kept on every instance so guesses can not be spread over them
+async fn record_failure(con: &mut Connection, station_id: Uuid, user_id: usize) -> RedisResult<()> {
+    redis::pipe()
+        .cmd("INCR").arg(failures_key(station_id, user_id)).ignore()
+        .cmd("EXPIRE").arg(failures_key(station_id, user_id)).arg(PASSWORD_FAILURE_WINDOW_SECS).ignore()
+        .query_async::<_, ()>(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    Ok(())
+}
+
+// Check the password of a password protected station, backing off after too many wrong ones
+async fn check_password(con: &mut Connection, station: &Station, user_id: usize, password: Option<&str>) -> Result<(), Denied> {
+    let password = password.ok_or(Denied::PasswordRequired)?;
+
+    match failures(con, station.id(), user_id).await {
+        Ok(failures) if failures >= MAX_PASSWORD_FAILURES => return Err(Denied::TooManyAttempts),
+        Ok(_) => {}
+        Err(e) => eprintln!("could not read password failures: {}", e),
+    }
+
+    match station.password_hash() {
+        Some(hash) if verify_password(hash, password).await => {
+            // Start counting from zero again once the user got it right
+            if let Err(e) = redis::cmd("DEL").arg(failures_key(station.id(), user_id)).query_async::<_, ()>(con).await {
+                eprintln!("could not reset password failures: {}", e);
+            }
+
+            Ok(())
+        }
+        _ => {
+            if let Err(e) = record_failure(con, station.id(), user_id).await {
+                eprintln!("could not count password failure: {}", e);
+            }
+
+            Err(Denied::WrongPassword)
+        }
+    }
+}
+
+// Decide if a user may join a station or see what it played
+// The owner and DJs always get in, banned users never do
+pub async fn admit(con: &mut Connection, station: &Station, username: &str, user_id: usize, password: Option<&str>) -> Result<(), Denied> {
+    match station.role_of_user(username, user_id) {
+        Role::Owner | Role::Dj => return Ok(()),
+        Role::Banned => return Err(Denied::Banned),
+        Role::Listener => {}
+    }
+
+    match station.visibility() {
+        Visibility::Public | Visibility::Unlisted => Ok(()),
+        Visibility::Password => check_password(con, station, user_id, password).await,
+        Visibility::InviteOnly if station.is_invited(user_id) => Ok(()),
+        Visibility::InviteOnly => Err(Denied::NotInvited),
+    }
+}
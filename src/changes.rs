@@ -0,0 +1,148 @@
+use std::sync::Arc;
+use std::time::Duration;
+use futures::StreamExt;
+use redis::aio::Connection;
+use tokio::time;
+use uuid::Uuid;
+use crate::{Clients, StationError};
+use crate::redis_direct::{get_con, get_str};
+use crate::station::{from_redis, StationManager};
+
+// Redis channel carrying the id of every station which was written
+// Only listened to when keyspace notifications are off, other services writing stations publish on it too
+const CHANGE_CHANNEL: &str = "station-changes";
+// Keyspace notification patterns for the keys other services write
+const STATION_PATTERN: &str = "__keyspace@*__:Station_*";
+const JOIN_CODE_PATTERN: &str = "__keyspace@*__:join-code:*";
+// Keyspace notification classes needed: keyspace events, generic, string and expired
+const KEYSPACE_FLAGS: [char; 4] = ['K', 'g', '$', 'x'];
+
+// Tell every instance a station was written
+pub async fn notify(con: &mut Connection, station_id: Uuid) {
+    let result = redis::cmd("PUBLISH")
+        .arg(CHANGE_CHANNEL)
+        .arg(station_id.to_string())
+        .query_async::<_, i64>(con)
+        .await;
+
+    if let Err(e) = result {
+        eprintln!("could not publish station change: {}", e);
+    }
+}
+
+// Add the notification classes we need to those the redis server already sends
+async fn enable_keyspace_events(con: &mut Connection) -> redis::RedisResult<()> {
+    let (_, mut flags): (String, String) = redis::cmd("CONFIG")
+        .arg("GET")
+        .arg("notify-keyspace-events")
+        .query_async(con)
+        .await?;
+
+    // A is an alias for every class apart from K, E, m and n
+    for flag in KEYSPACE_FLAGS {
+        if !flags.contains(flag) && (flag == 'K' || !flags.contains('A')) {
+            flags.push(flag);
+        }
+    }
+
+    redis::cmd("CONFIG")
+        .arg("SET")
+        .arg("notify-keyspace-events")
+        .arg(flags)
+        .query_async(con)
+        .await
+}
+
+// Reload a station with local members and tell them what changed
+async fn reload(stations: &StationManager, clients: &Clients, con: &mut Connection, station_id: Uuid) {
+    if !stations.has_members(station_id).await {
+        return;
+    }
+
+    match from_redis(station_id, con).await {
+        Ok(station) => stations.queue_updated(station_id, station.media_queue(), clients).await,
+        Err(StationError::NotFound(_)) => stations.station_deleted(station_id, clients, con).await,
+        Err(e) => eprintln!("could not reload station: {}", e),
+    }
+}
+
+// Work out which station a change is about
+// Keyspace notifications name the key in the channel, the change channel sends the station id
+async fn changed_station(con: &mut Connection, channel: &str, payload: &str) -> Option<Uuid> {
+    if channel == CHANGE_CHANNEL {
+        return Uuid::parse_str(payload).ok();
+    }
+
+    let key = channel.split_once("__:")?.1;
+    if let Some(station_id) = key.strip_prefix("Station_") {
+        return Uuid::parse_str(station_id).ok();
+    }
+
+    // A join code changed, reload the station it points to now
+    if key.starts_with("join-code:") {
+        let station_id = get_str(con, key).await.ok()?;
+        return Uuid::parse_str(&station_id).ok();
+    }
+
+    None
+}
+
+// Reload stations as soon as any instance or other service writes them
+pub async fn listen(stations: Arc<StationManager>, clients: Clients, redis_client: redis::Client, keyspace_events: bool) {
+    loop {
+        // Connection for reloading stations
+        let mut redis_con = match get_con(redis_client.clone()).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis for station changes");
+                time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        // Without keyspace notifications only changes published on the change channel are seen
+        if keyspace_events {
+            if let Err(e) = enable_keyspace_events(&mut redis_con).await {
+                eprintln!("could not enable keyspace notifications: {}", e);
+            }
+        }
+
+        // Establish a dedicated connection for the subscription
+        let mut pubsub = match get_con(redis_client.clone()).await {
+            Ok(v) => v.into_pubsub(),
+            Err(_) => {
+                eprintln!("could not connect to redis for station changes");
+                time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        // Keyspace notifications also fire for writes which are published on the change channel,
+        // so only one of them is listened to or every write would reload the station twice
+        let subscribed = if keyspace_events {
+            pubsub.psubscribe(&[STATION_PATTERN, JOIN_CODE_PATTERN]).await
+        } else {
+            pubsub.subscribe(CHANGE_CHANNEL).await
+        };
+        if let Err(e) = subscribed {
+            eprintln!("could not subscribe to station changes: {}", e);
+            time::sleep(Duration::from_secs(5)).await;
+            continue;
+        }
+
+        let mut messages = pubsub.on_message();
+        while let Some(msg) = messages.next().await {
+            let payload: String = match msg.get_payload() {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+
+            if let Some(station_id) = changed_station(&mut redis_con, msg.get_channel_name(), &payload).await {
+                reload(&stations, &clients, &mut redis_con, station_id).await;
+            }
+        }
+
+        eprintln!("station change subscription closed, reconnecting");
+        time::sleep(Duration::from_secs(5)).await;
+    }
+}
@@ -0,0 +1,332 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use redis::aio::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::from_str;
+use uuid::Uuid;
+use crate::{Clients, fanout, ws};
+use crate::DirectError::RedisCMDError;
+use crate::message_receive::Receiver;
+use crate::redis_direct::get_con;
+use crate::station::{from_redis, Role, Station, StationManager};
+use crate::timer::unix_millis;
+
+// Maximum amount of characters in a chat message
+const MAX_MESSAGE_LEN: usize = 500;
+// Default length of a mute
+const DEFAULT_MUTE_SECS: u64 = 300;
+// Longest a mute may last, a week
+const MAX_MUTE_SECS: u64 = 7 * 24 * 60 * 60;
+
+// Chat message sent by a listener
+#[derive(Deserialize, Debug)]
+pub struct ChatRequest {
+    #[serde(rename = "stationId")]
+    station_id: Option<Uuid>,
+    #[serde(rename = "text")]
+    text: String,
+}
+
+impl ChatRequest {
+    // Get the message without surrounding whitespace, if it is not empty or too long
+    pub fn text(&self) -> Option<&str> {
+        let text = self.text.trim();
+        if text.is_empty() || text.chars().count() > MAX_MESSAGE_LEN {
+            return None;
+        }
+
+        Some(text)
+    }
+}
+
+// Chat message sent to every listener of a station
+#[derive(Serialize, Debug)]
+struct ChatMessage<'a> {
+    #[serde(rename = "stationId")]
+    station_id: Uuid,
+    #[serde(rename = "messageId")]
+    message_id: String,
+    #[serde(rename = "userId")]
+    user_id: usize,
+    #[serde(rename = "displayName")]
+    display_name: &'a str,
+    #[serde(rename = "text")]
+    text: &'a str,
+    #[serde(rename = "timestamp")]
+    timestamp: u64,
+}
+
+// Moderation command sent by the owner or a DJ of a station
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action")]
+pub enum ModerationRequest {
+    #[serde(rename = "delete")]
+    Delete {
+        #[serde(rename = "stationId")]
+        station_id: Option<Uuid>,
+        #[serde(rename = "messageId")]
+        message_id: String,
+    },
+    #[serde(rename = "mute")]
+    Mute {
+        #[serde(rename = "stationId")]
+        station_id: Option<Uuid>,
+        #[serde(rename = "userId")]
+        user_id: usize,
+        #[serde(rename = "durationSecs")]
+        duration_secs: Option<u64>,
+    },
+    #[serde(rename = "kick")]
+    Kick {
+        #[serde(rename = "stationId")]
+        station_id: Option<Uuid>,
+        #[serde(rename = "userId")]
+        user_id: usize,
+    },
+}
+
+// Sent to every listener when a moderator deletes a message
+#[derive(Serialize, Debug)]
+struct ChatDeleted<'a> {
+    #[serde(rename = "stationId")]
+    station_id: Uuid,
+    #[serde(rename = "messageId")]
+    message_id: &'a str,
+}
+
+// Sent to every listener when a moderator mutes a user
+#[derive(Serialize, Debug)]
+struct ChatMuted {
+    #[serde(rename = "stationId")]
+    station_id: Uuid,
+    #[serde(rename = "userId")]
+    user_id: usize,
+    #[serde(rename = "until")]
+    until: u64,
+}
+
+impl ModerationRequest {
+    fn station_id(&self) -> Option<Uuid> {
+        match self {
+            ModerationRequest::Delete { station_id, .. } => *station_id,
+            ModerationRequest::Mute { station_id, .. } => *station_id,
+            ModerationRequest::Kick { station_id, .. } => *station_id,
+        }
+    }
+
+    // Ensure the command could be applied, returning the reason it can not
+    pub fn check(&self) -> Result<(), &'static str> {
+        // Redis refuses keys expiring after 0 seconds
+        if let ModerationRequest::Mute { duration_secs: Some(0), .. } = self {
+            return Err("mutes must last at least a second");
+        }
+
+        Ok(())
+    }
+
+    // User the command acts on, deleting a message does not act on a user
+    fn target_user_id(&self) -> Option<usize> {
+        match self {
+            ModerationRequest::Delete { .. } => None,
+            ModerationRequest::Mute { user_id, .. } => Some(*user_id),
+            ModerationRequest::Kick { user_id, .. } => Some(*user_id),
+        }
+    }
+}
+
+// Work out the role of the user a moderation command acts on
+// A connection of the user on this instance also tells the username, which finds owners of older stations
+async fn target_role(station: &Station, user_id: usize, clients: &Clients) -> Role {
+    let clients_lock = clients.read().await;
+    match clients_lock.values().find(|c| c.user_id == user_id) {
+        Some(c) => station.role_of(c),
+        None => station.role_of_id(user_id),
+    }
+}
+
+// Construct the redis key marking a user as muted in a station
+fn mute_key(station_id: Uuid, user_id: usize) -> String {
+    format!("station-mute:{}:{}", station_id, user_id)
+}
+
+// Check if a user is muted in a station
+async fn is_muted(con: &mut Connection, station_id: Uuid, user_id: usize) -> bool {
+    redis::cmd("EXISTS")
+        .arg(mute_key(station_id, user_id))
+        .query_async::<_, bool>(con)
+        .await
+        .unwrap_or(false)
+}
+
+// Receiver for chat messages sent to a station
+// How often users may chat is limited by the station_chat rate limit
+pub struct ChatReceiver {
+    pub stations: Arc<StationManager>,
+}
+
+#[async_trait]
+impl Receiver for ChatReceiver {
+    async fn receive_msg(&self, id: &str, msg: &str, clients: &Clients, redis_client: redis::Client) {
+        let request: ChatRequest = match from_str(msg) {
+            Ok(v) => v,
+            Err(_) => {
+                ws::send_error(clients, id, "invalid_request", "expected a chat message").await;
+                return;
+            }
+        };
+
+        // Ensure the client is in the station
+        let station_id = match self.stations.resolve_station(id, request.station_id).await {
+            Some(v) => v,
+            None => {
+                ws::send_error(clients, id, "not_in_station", "join the station before chatting").await;
+                return;
+            }
+        };
+
+        let text = match request.text() {
+            Some(v) => v,
+            None => {
+                let error = format!("chat messages must be between 1 and {} characters", MAX_MESSAGE_LEN);
+                ws::send_error(clients, id, "invalid_message", &error).await;
+                return;
+            }
+        };
+
+        let listener = match clients.read().await.get(id) {
+            Some(v) => v.listener(),
+            None => return,
+        };
+
+        // Establish connection to redis
+        let mut redis_con = match get_con(redis_client).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis");
+                return;
+            }
+        };
+
+        if is_muted(&mut redis_con, station_id, listener.user_id).await {
+            ws::send_error(clients, id, "muted", "you are muted in this station").await;
+            return;
+        }
+
+        let message = ChatMessage {
+            station_id,
+            message_id: Uuid::new_v4().as_simple().to_string(),
+            user_id: listener.user_id,
+            display_name: &listener.display_name,
+            text,
+            timestamp: unix_millis(),
+        };
+
+        // Send the message to every listener of the station
+        if let Err(e) = fanout::send_json(&mut redis_con, station_id, "station_chat", &message).await {
+            eprintln!("could not send chat message: {}", e);
+        }
+    }
+}
+
+// Receiver for moderation commands from the owner or DJs of a station
+pub struct ModerationReceiver {
+    pub stations: Arc<StationManager>,
+}
+#[async_trait]
+impl Receiver for ModerationReceiver {
+    async fn receive_msg(&self, id: &str, msg: &str, clients: &Clients, redis_client: redis::Client) {
+        let request: ModerationRequest = match from_str(msg) {
+            Ok(v) => v,
+            Err(_) => {
+                ws::send_error(clients, id, "invalid_request", "expected a moderation command").await;
+                return;
+            }
+        };
+        if let Err(reason) = request.check() {
+            ws::send_error(clients, id, "invalid_duration", reason).await;
+            return;
+        }
+
+        // Ensure the client is in the station
+        let station_id = match self.stations.resolve_station(id, request.station_id()).await {
+            Some(v) => v,
+            None => {
+                ws::send_error(clients, id, "not_in_station", "join the station before moderating").await;
+                return;
+            }
+        };
+
+        // Establish connection to redis
+        let mut redis_con = match get_con(redis_client).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis");
+                return;
+            }
+        };
+
+        // Only the owner and DJs of the station may moderate it
+        let station = match from_redis(station_id, &mut redis_con).await {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+        let role = match clients.read().await.get(id) {
+            Some(c) => station.role_of(c),
+            None => return,
+        };
+        if !role.can_manage() {
+            ws::send_error(clients, id, "forbidden", "only the station owner and DJs can moderate").await;
+            return;
+        }
+
+        // Moderators may only mute or kick users below them
+        if let Some(target_id) = request.target_user_id() {
+            if !role.outranks(target_role(&station, target_id, clients).await) {
+                ws::send_error(clients, id, "forbidden", "you can not moderate a user with the same or a higher role").await;
+                return;
+            }
+        }
+
+        let result = match request {
+            ModerationRequest::Delete { message_id, .. } => {
+                // Tell every listener to hide the message
+                fanout::send_json(&mut redis_con, station_id, "chat_deleted", &ChatDeleted {
+                    station_id,
+                    message_id: &message_id,
+                }).await
+            }
+            ModerationRequest::Mute { user_id, duration_secs, .. } => {
+                // Mutes longer than the maximum are cut down to it
+                let duration_secs = duration_secs.unwrap_or(DEFAULT_MUTE_SECS).min(MAX_MUTE_SECS);
+
+                // Mark the user as muted until the key expires
+                match redis::cmd("SET")
+                    .arg(mute_key(station_id, user_id))
+                    .arg(1)
+                    .arg("EX")
+                    .arg(duration_secs)
+                    .query_async::<_, ()>(&mut redis_con)
+                    .await {
+                    Ok(_) => fanout::send_json(&mut redis_con, station_id, "chat_muted", &ChatMuted {
+                        station_id,
+                        user_id,
+                        until: unix_millis().saturating_add(duration_secs.saturating_mul(1000)),
+                    }).await,
+                    Err(e) => Err(RedisCMDError(e).into()),
+                }
+            }
+            ModerationRequest::Kick { user_id, .. } => {
+                // Remove the user from the station on every instance
+                fanout::kick(&mut redis_con, station_id, user_id).await
+            }
+        };
+
+        if let Err(e) = result {
+            eprintln!("could not apply moderation command: {}", e);
+            ws::send_error(clients, id, "unavailable", "the moderation command could not be applied").await;
+        }
+    }
+}
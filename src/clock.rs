@@ -0,0 +1,54 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::from_str;
+use crate::{Clients, ws};
+use crate::message_receive::Receiver;
+use crate::timer::unix_millis;
+
+// Time sync request, the client stamps it with its own clock
+#[derive(Deserialize, Debug)]
+pub struct TimeSyncRequest {
+    #[serde(rename = "clientSendTime")]
+    client_send_time: u64,
+}
+
+// Time sync response in the style of NTP
+// From the four timestamps the client can work out its clock offset and the round trip time:
+// offset = ((serverReceiveTime - clientSendTime) + (serverSendTime - clientReceiveTime)) / 2
+// roundTrip = (clientReceiveTime - clientSendTime) - (serverSendTime - serverReceiveTime)
+#[derive(Serialize, Debug)]
+struct TimeSyncResponse {
+    #[serde(rename = "clientSendTime")]
+    client_send_time: u64,
+    #[serde(rename = "serverReceiveTime")]
+    server_receive_time: u64,
+    #[serde(rename = "serverSendTime")]
+    server_send_time: u64,
+}
+
+// Receiver for clients synchronizing their clock with the server
+pub struct TimeSyncReceiver;
+#[async_trait]
+impl Receiver for TimeSyncReceiver {
+    async fn receive_msg(&self, id: &str, msg: &str, clients: &Clients, redis_client: redis::Client) {
+        self.receive_timed(id, msg, unix_millis(), clients, redis_client).await
+    }
+
+    // The receive time is taken when the frame arrives, before it waits on the rate limiter and dispatch
+    async fn receive_timed(&self, id: &str, msg: &str, server_receive_time: u64, clients: &Clients, _redis_client: redis::Client) {
+        let request: TimeSyncRequest = match from_str(msg) {
+            Ok(v) => v,
+            Err(_) => {
+                ws::send_error(clients, id, "invalid_request", "expected a time sync request").await;
+                return;
+            }
+        };
+
+        let response = TimeSyncResponse {
+            client_send_time: request.client_send_time,
+            server_receive_time,
+            server_send_time: unix_millis(),
+        };
+        ws::send_json(clients, id, "time_sync", &response).await;
+    }
+}
@@ -0,0 +1,119 @@
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use crate::origins::Origins;
+use crate::publishers::ApiKeys;
+use crate::ratelimit::RateLimits;
+use crate::station::StationMode;
+
+// Settings read from the environment when the server starts
+#[derive(Debug, Clone)]
+pub struct Config {
+    // Fraction of current listeners which must vote to skip the playing media
+    pub skip_threshold: f64,
+    // Turn on redis keyspace notifications to notice stations written by other services
+    // Off by default since it changes the config of a redis server which may be shared
+    pub keyspace_events: bool,
+    // Seconds a join code works for when the request does not say
+    pub join_code_ttl_secs: u64,
+    // Most listeners allowed in a station which does not set its own limit, 0 means no limit
+    pub max_listeners: usize,
+    // Station mode of clients which do not pick one when registering
+    // Multi by default, clients have always been able to be in several stations at once
+    pub station_mode: StationMode,
+    // Token bucket limits for each receiver id and HTTP route
+    pub rate_limits: RateLimits,
+    // Rate limited frames within a minute before a connection is closed, 0 never closes it
+    pub rate_limit_strikes: u32,
+    // Largest websocket frame accepted from a client in bytes
+    pub max_frame_bytes: usize,
+    // Largest websocket message accepted from a client in bytes, after joining its frames
+    pub max_message_bytes: usize,
+    // Whether /publish needs an api key or signature, only turn off for local development
+    pub publish_auth: bool,
+    // Publish keys as a json array of {id, secret, topics, userIds}, more can be stored in redis
+    pub publish_keys: ApiKeys,
+    // Websites allowed to use the REST routes and open websockets, * allows every website
+    pub allowed_origins: Origins,
+    // Address the server listens on
+    pub listen_addr: SocketAddr,
+    // Host and port clients connect to, used in the websocket urls handed out on register
+    // Defaults to the listen address, which has to be a specific one then
+    pub public_host: String,
+    // PEM files of the certificate chain and private key, TLS is only served when both are set
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    // Seconds between checking the certificate files for changes, 0 never reloads them
+    pub tls_reload_secs: u64,
+    // Seconds a registration waits for its websocket before it is removed, 0 keeps it forever
+    pub registration_ttl_secs: u64,
+    // Most registrations without a websocket a single user may have, 0 means no limit
+    pub max_pending_registrations: usize,
+    // Secret the backend signs user tokens with, clients register with a token instead of naming themselves
+    pub user_token_secret: String,
+    // Bearer token monitoring sends to read the metrics, only local requests may read them when missing
+    pub metrics_token: Option<String>,
+}
+
+impl Config {
+    // Load every setting, using defaults for those which are not set
+    pub fn from_env() -> Config {
+        let listen_addr = env_or("VRADIO_LISTEN_ADDR", SocketAddr::from(([127, 0, 0, 1], 8000)));
+        let public_host = match env::var("VRADIO_PUBLIC_HOST").ok().filter(|h| !h.is_empty()) {
+            Some(v) => v,
+            // Clients can not connect to 0.0.0.0 or ::, the urls handed out would never work
+            None if listen_addr.ip().is_unspecified() => panic!("VRADIO_PUBLIC_HOST must be set when listening on {}", listen_addr),
+            None => listen_addr.to_string(),
+        };
+        // Without the secret no client could ever register
+        let user_token_secret = match env::var("VRADIO_USER_TOKEN_SECRET").ok().filter(|s| !s.is_empty()) {
+            Some(v) => v,
+            None => panic!("VRADIO_USER_TOKEN_SECRET must be set to the secret the backend signs user tokens with"),
+        };
+
+        Config {
+            skip_threshold: env_or("VRADIO_SKIP_THRESHOLD", 0.5),
+            keyspace_events: env_or("VRADIO_KEYSPACE_EVENTS", false),
+            join_code_ttl_secs: env_or("VRADIO_JOIN_CODE_TTL_SECS", 7 * 24 * 60 * 60),
+            max_listeners: env_or("VRADIO_MAX_LISTENERS", 0),
+            station_mode: env_or("VRADIO_STATION_MODE", StationMode::Multi),
+            rate_limits: env_or("VRADIO_RATE_LIMITS", RateLimits::default()),
+            rate_limit_strikes: env_or("VRADIO_RATE_LIMIT_STRIKES", 20),
+            max_frame_bytes: env_or("VRADIO_MAX_FRAME_BYTES", 16 * 1024),
+            max_message_bytes: env_or("VRADIO_MAX_MESSAGE_BYTES", 64 * 1024),
+            publish_auth: env_or("VRADIO_PUBLISH_AUTH", true),
+            publish_keys: env_or("VRADIO_PUBLISH_KEYS", ApiKeys::default()),
+            allowed_origins: env_or("VRADIO_ALLOWED_ORIGINS", Origins::default()),
+            listen_addr,
+            public_host,
+            tls_cert_path: env::var("VRADIO_TLS_CERT").ok().map(PathBuf::from),
+            tls_key_path: env::var("VRADIO_TLS_KEY").ok().map(PathBuf::from),
+            tls_reload_secs: env_or("VRADIO_TLS_RELOAD_SECS", 60),
+            registration_ttl_secs: env_or("VRADIO_REGISTRATION_TTL_SECS", 60),
+            max_pending_registrations: env_or("VRADIO_MAX_PENDING_REGISTRATIONS", 5),
+            user_token_secret,
+            metrics_token: env::var("VRADIO_METRICS_TOKEN").ok().filter(|s| !s.is_empty()),
+        }
+    }
+
+    // Base of the websocket urls handed out to clients
+    pub fn ws_base_url(&self) -> String {
+        let scheme = if self.tls_cert_path.is_some() && self.tls_key_path.is_some() { "wss" } else { "ws" };
+        format!("{}://{}/ws/", scheme, self.public_host)
+    }
+}
+
+// Read a setting from the environment, falling back to a default when it is missing or invalid
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(v) => match v.parse() {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("invalid value for {}, using default", key);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
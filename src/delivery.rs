@@ -0,0 +1,207 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::sync::RwLock;
+use warp::ws::Message;
+use crate::Clients;
+use crate::message_receive::Receiver;
+
+// Maximum amount of unacknowledged messages kept for a single user
+const MAX_PENDING_PER_USER: usize = 100;
+// Maximum amount of message ids a delivery status is kept for
+const MAX_TRACKED_MESSAGES: usize = 1000;
+// Time an unacknowledged message is kept for a user who may be offline
+const PENDING_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+// Times a message is sent to a connected user before giving up on an acknowledgement
+const MAX_ATTEMPTS: u32 = 10;
+
+// Message waiting for an acknowledgement from a user
+struct PendingMessage {
+    message_id: String,
+    // Topic the message was published to, only connections listening to it receive the message
+    topic: String,
+    message: Message,
+    created: Instant,
+    // Times the message was sent to a connection of the user
+    attempts: u32,
+}
+
+impl PendingMessage {
+    // Check if the message should no longer be sent
+    fn expired(&self, now: Instant) -> bool {
+        self.attempts >= MAX_ATTEMPTS || now.duration_since(self.created) > PENDING_TTL
+    }
+}
+
+// Delivery status of a tracked message
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct DeliveryStatus {
+    // Topic and user the message was published to, only keys allowed to publish there may see the status
+    #[serde(skip)]
+    pub topic: String,
+    #[serde(skip)]
+    pub user_id: Option<usize>,
+    #[serde(rename = "recipients")]
+    recipients: HashSet<usize>,
+    #[serde(rename = "acknowledged")]
+    acknowledged: HashSet<usize>,
+}
+
+// Structure for tracking messages published with an id until they are acknowledged
+pub struct DeliveryTracker {
+    // Unacknowledged messages for each user id, kept across reconnects
+    pending: RwLock<HashMap<usize, VecDeque<PendingMessage>>>,
+    // Status of each tracked message id
+    statuses: RwLock<HashMap<String, DeliveryStatus>>,
+    // Order message ids were tracked in, used to forget the oldest statuses
+    order: RwLock<VecDeque<String>>,
+}
+
+impl DeliveryTracker {
+    // Boilerplate for creating a new instance
+    pub fn new() -> DeliveryTracker {
+        DeliveryTracker {
+            pending: RwLock::new(HashMap::new()),
+            statuses: RwLock::new(HashMap::new()),
+            order: RwLock::new(VecDeque::new()),
+        }
+    }
+
+    // Start tracking a message that is about to be sent to the given users
+    // Users which are offline are included, they receive the message when they connect
+    // The first attempt is counted for users which were sent the message already
+    pub async fn track(&self, message_id: &str, topic: &str, user_id: Option<usize>, message: &Message, user_ids: &HashSet<usize>, sent_to: &HashSet<usize>) {
+        let now = Instant::now();
+        let mut pending_lock = self.pending.write().await;
+        for user_id in user_ids {
+            let user_pending = pending_lock.entry(*user_id).or_default();
+
+            // Replace an older message with the same id
+            user_pending.retain(|p| p.message_id != message_id);
+            user_pending.push_back(PendingMessage {
+                message_id: message_id.to_string(),
+                topic: topic.to_string(),
+                message: message.clone(),
+                created: now,
+                attempts: u32::from(sent_to.contains(user_id)),
+            });
+
+            // Drop the oldest message when the buffer is full
+            if user_pending.len() > MAX_PENDING_PER_USER {
+                user_pending.pop_front();
+            }
+        }
+
+        let mut statuses_lock = self.statuses.write().await;
+        let mut order_lock = self.order.write().await;
+
+        // Record the recipients of the message
+        if statuses_lock.insert(message_id.to_string(), DeliveryStatus {
+            topic: topic.to_string(),
+            user_id,
+            recipients: user_ids.clone(),
+            acknowledged: HashSet::new(),
+        }).is_none() {
+            order_lock.push_back(message_id.to_string());
+        }
+
+        // Forget the oldest statuses
+        while order_lock.len() > MAX_TRACKED_MESSAGES {
+            if let Some(old_id) = order_lock.pop_front() {
+                statuses_lock.remove(&old_id);
+            }
+        }
+    }
+
+    // Mark a message as received by a user
+    pub async fn acknowledge(&self, message_id: &str, user_id: usize) {
+        if let Some(user_pending) = self.pending.write().await.get_mut(&user_id) {
+            user_pending.retain(|p| p.message_id != message_id);
+        }
+
+        if let Some(status) = self.statuses.write().await.get_mut(message_id) {
+            if status.recipients.contains(&user_id) {
+                status.acknowledged.insert(user_id);
+            }
+        }
+    }
+
+    // Get the delivery status of a message
+    pub async fn status(&self, message_id: &str) -> Option<DeliveryStatus> {
+        self.statuses.read().await.get(message_id).cloned()
+    }
+
+    // Send every unacknowledged message of a user to one connection
+    pub async fn resend_to(&self, user_id: usize, client_id: &str, clients: &Clients) {
+        let mut pending_lock = self.pending.write().await;
+        let user_pending = match pending_lock.get_mut(&user_id) {
+            Some(v) => v,
+            None => return,
+        };
+
+        let now = Instant::now();
+        user_pending.retain(|p| !p.expired(now));
+
+        if let Some(client) = clients.read().await.get(client_id) {
+            if let Some(sender) = &client.sender {
+                for pending in user_pending.iter_mut().filter(|p| client.topics.contains(&p.topic)) {
+                    if sender.send(Ok(pending.message.clone())).is_ok() {
+                        pending.attempts += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    // Send every unacknowledged message again to all connections of its user listening to its topic
+    // Messages which expired or ran out of attempts are dropped
+    pub async fn retry(&self, clients: &Clients) {
+        let now = Instant::now();
+        let mut pending_lock = self.pending.write().await;
+        pending_lock.retain(|_, user_pending| {
+            user_pending.retain(|p| !p.expired(now));
+            !user_pending.is_empty()
+        });
+
+        let clients_lock = clients.read().await;
+        for (user_id, user_pending) in pending_lock.iter_mut() {
+            let connections: Vec<_> = clients_lock.values()
+                .filter(|c| c.user_id == *user_id)
+                .filter_map(|c| c.sender.as_ref().map(|s| (s, &c.topics)))
+                .collect();
+
+            for pending in user_pending.iter_mut() {
+                let mut sent = false;
+                for (sender, topics) in &connections {
+                    if topics.contains(&pending.topic) && sender.send(Ok(pending.message.clone())).is_ok() {
+                        sent = true;
+                    }
+                }
+
+                // Only count attempts which reached a connection, offline users keep their messages until they expire
+                if sent {
+                    pending.attempts += 1;
+                }
+            }
+        }
+    }
+}
+
+// Receiver for acknowledging a published message
+pub struct AckReceiver {
+    pub deliveries: Arc<DeliveryTracker>,
+}
+#[async_trait]
+impl Receiver for AckReceiver {
+    async fn receive_msg(&self, id: &str, msg: &str, clients: &Clients, _redis_client: redis::Client) {
+        // Find which user the connection belongs to
+        let user_id = match clients.read().await.get(id) {
+            Some(v) => v.user_id,
+            None => return,
+        };
+
+        self.deliveries.acknowledge(msg.trim_end(), user_id).await;
+    }
+}
@@ -0,0 +1,162 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use warp::ws::Message;
+
+// Binary body of a published event, data is base64 encoded
+#[derive(Deserialize, Debug, Clone)]
+pub struct BinaryPayload {
+    #[serde(rename = "contentType")]
+    pub content_type: String,
+    #[serde(rename = "data")]
+    pub data: String,
+}
+
+impl BinaryPayload {
+    // Decode the base64 data sent by the publisher
+    pub fn decode(&self) -> Option<Vec<u8>> {
+        STANDARD.decode(&self.data).ok()
+    }
+}
+
+// Body of a published event
+#[derive(Debug, Clone)]
+pub enum Payload {
+    // Any json value, sent to clients as a text frame
+    Json(Value),
+    // Base64 encoded data, sent to clients as a binary frame
+    Binary(BinaryPayload),
+}
+
+// Metadata the server wraps around every published payload
+#[derive(Serialize, Debug)]
+pub struct Envelope<'a> {
+    #[serde(rename = "id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<&'a str>,
+    // Id of the entry in the topic history, can be used to replay from this message
+    #[serde(rename = "streamId", skip_serializing_if = "Option::is_none")]
+    pub stream_id: Option<&'a str>,
+    #[serde(rename = "topic")]
+    pub topic: &'a str,
+    #[serde(rename = "timestamp")]
+    pub timestamp: u64,
+}
+
+// Envelope with a json payload, sent as a text frame
+#[derive(Serialize, Debug)]
+struct JsonFrame<'a> {
+    #[serde(flatten)]
+    envelope: &'a Envelope<'a>,
+    #[serde(rename = "message")]
+    message: &'a Value,
+}
+
+// Header put in front of the raw bytes of a binary frame
+#[derive(Serialize, Debug)]
+struct BinaryHeader<'a> {
+    #[serde(flatten)]
+    envelope: &'a Envelope<'a>,
+    #[serde(rename = "contentType")]
+    content_type: &'a str,
+}
+
+impl<'a> Envelope<'a> {
+    // Wrap a payload in the envelope and create the frame for it
+    pub fn message(&self, payload: &Payload) -> Option<Message> {
+        match payload {
+            Payload::Json(message) => self.json_message(message),
+            Payload::Binary(binary) => self.binary_message(binary),
+        }
+    }
+
+    // Create a text frame holding the envelope and a json value
+    fn json_message(&self, message: &Value) -> Option<Message> {
+        let frame = JsonFrame {
+            envelope: self,
+            message,
+        };
+
+        serde_json::to_string(&frame).ok().map(Message::text)
+    }
+
+    // Create a binary frame from a binary payload
+    // The frame is laid out as a 4 byte big endian header length, the json header and then the raw bytes
+    fn binary_message(&self, payload: &BinaryPayload) -> Option<Message> {
+        let data = payload.decode()?;
+
+        let header = BinaryHeader {
+            envelope: self,
+            content_type: &payload.content_type,
+        };
+        let header = serde_json::to_vec(&header).ok()?;
+
+        // Build the frame
+        let mut frame = Vec::with_capacity(4 + header.len() + data.len());
+        frame.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(&data);
+
+        Some(Message::binary(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope() -> Envelope<'static> {
+        Envelope {
+            id: Some("1"),
+            stream_id: None,
+            topic: "news",
+            timestamp: 1000,
+        }
+    }
+
+    #[test]
+    fn binary_frame_layout() {
+        let data = [0u8, 1, 2, 255];
+        let payload = Payload::Binary(BinaryPayload {
+            content_type: "application/octet-stream".to_string(),
+            data: STANDARD.encode(data),
+        });
+        let frame = envelope().message(&payload).expect("payload is valid base64");
+        assert!(frame.is_binary());
+        let frame = frame.as_bytes();
+
+        // 4 byte big endian header length, then the json header, then the raw bytes
+        let header_len = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+        let header: Value = serde_json::from_slice(&frame[4..4 + header_len]).expect("header is json");
+        assert_eq!(header, serde_json::json!({
+            "id": "1",
+            "topic": "news",
+            "timestamp": 1000,
+            "contentType": "application/octet-stream",
+        }));
+        assert_eq!(&frame[4 + header_len..], &data);
+    }
+
+    #[test]
+    fn binary_frame_needs_base64() {
+        let payload = Payload::Binary(BinaryPayload {
+            content_type: "image/png".to_string(),
+            data: "not base64!".to_string(),
+        });
+
+        assert!(envelope().message(&payload).is_none());
+    }
+
+    #[test]
+    fn json_frame() {
+        let frame = envelope().message(&Payload::Json(serde_json::json!({"text": "hi"}))).unwrap();
+        let frame: Value = serde_json::from_str(frame.to_str().expect("json is sent as text")).unwrap();
+
+        assert_eq!(frame, serde_json::json!({
+            "id": "1",
+            "topic": "news",
+            "timestamp": 1000,
+            "message": {"text": "hi"},
+        }));
+    }
+}
@@ -0,0 +1,122 @@
+use std::sync::Arc;
+use std::time::Duration;
+use futures::StreamExt;
+use redis::aio::Connection;
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use uuid::Uuid;
+use warp::ws::Message;
+use crate::Clients;
+use crate::DirectError::RedisCMDError;
+use crate::redis_direct::{get_con, Result};
+use crate::station::StationManager;
+
+// Redis channel station events are published on so every instance sees them
+const STATION_CHANNEL: &str = "station-events";
+
+// Event published for a station, handled by every instance with members in it
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum StationEvent {
+    // Send a frame to every member of the station
+    #[serde(rename = "frame")]
+    Frame {
+        #[serde(rename = "stationId")]
+        station_id: Uuid,
+        #[serde(rename = "frame")]
+        frame: String,
+    },
+    // A place in the station opened up or the waiting room changed
+    #[serde(rename = "waiting_room")]
+    WaitingRoom {
+        #[serde(rename = "stationId")]
+        station_id: Uuid,
+    },
+    // Remove every connection of a user from the station
+    #[serde(rename = "kick")]
+    Kick {
+        #[serde(rename = "stationId")]
+        station_id: Uuid,
+        #[serde(rename = "userId")]
+        user_id: usize,
+    },
+}
+
+// Send a frame to the members of a station on every instance
+pub async fn send(con: &mut Connection, station_id: Uuid, frame: String) -> Result<()> {
+    publish(con, &StationEvent::Frame { station_id, frame }).await
+}
+
+// Send a frame made of a key and a json value to the members of a station on every instance
+pub async fn send_json<T: Serialize>(con: &mut Connection, station_id: Uuid, key: &str, value: &T) -> Result<()> {
+    let as_json = serde_json::to_string(value).unwrap_or_default();
+    send(con, station_id, key.to_string() + "=" + &as_json).await
+}
+
+// Remove a user from a station on every instance
+pub async fn kick(con: &mut Connection, station_id: Uuid, user_id: usize) -> Result<()> {
+    publish(con, &StationEvent::Kick { station_id, user_id }).await
+}
+
+// Let waiting clients into a station on every instance and update their positions
+pub async fn waiting_room_changed(con: &mut Connection, station_id: Uuid) -> Result<()> {
+    publish(con, &StationEvent::WaitingRoom { station_id }).await
+}
+
+async fn publish(con: &mut Connection, event: &StationEvent) -> Result<()> {
+    let as_json = serde_json::to_string(event).unwrap_or_default();
+
+    redis::cmd("PUBLISH")
+        .arg(STATION_CHANNEL)
+        .arg(as_json)
+        .query_async::<_, i64>(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    Ok(())
+}
+
+// Handle station events from every instance for the local members of each station
+pub async fn listen(stations: Arc<StationManager>, clients: Clients, redis_client: redis::Client) {
+    loop {
+        // Establish a dedicated connection for the subscription
+        let mut pubsub = match get_con(redis_client.clone()).await {
+            Ok(v) => v.into_pubsub(),
+            Err(_) => {
+                eprintln!("could not connect to redis for station events");
+                time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        if let Err(e) = pubsub.subscribe(STATION_CHANNEL).await {
+            eprintln!("could not subscribe to station events: {}", e);
+            time::sleep(Duration::from_secs(5)).await;
+            continue;
+        }
+
+        let mut messages = pubsub.on_message();
+        while let Some(msg) = messages.next().await {
+            let payload: String = match msg.get_payload() {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+
+            match serde_json::from_str(&payload) {
+                Ok(StationEvent::Frame { station_id, frame }) => {
+                    stations.broadcast(station_id, Message::text(frame), &clients).await;
+                }
+                Ok(StationEvent::WaitingRoom { station_id }) => {
+                    stations.admit_waiting(station_id, &clients, redis_client.clone()).await;
+                }
+                Ok(StationEvent::Kick { station_id, user_id }) => {
+                    stations.kick(station_id, user_id, &clients, redis_client.clone()).await;
+                }
+                Err(e) => eprintln!("could not read station event: {}", e),
+            }
+        }
+
+        eprintln!("station event subscription closed, reconnecting");
+        time::sleep(Duration::from_secs(5)).await;
+    }
+}
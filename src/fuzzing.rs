@@ -0,0 +1,87 @@
+use serde::de::DeserializeOwned;
+use crate::access::JoinRequest;
+use crate::chat::{ChatRequest, ModerationRequest};
+use crate::clock::TimeSyncRequest;
+use crate::handler::RegisterRequest;
+use crate::lifecycle::{self, CapacityRequest, CreateStationRequest, ManageRequest, RenameStationRequest, RotateCodeRequest, VisibilityRequest};
+use crate::played::{HistoryRequest, PlayAgainRequest};
+use crate::queue::{QueueEdit, QueueRequest};
+use crate::roles::RoleRequest;
+use crate::votes::{MediaVoteRequest, SkipVoteRequest};
+use crate::validate::{self, Frame};
+use crate::ws::TopicsRequest;
+
+// Check a payload decodes the way a receiver decodes it
+fn decodes<T: DeserializeOwned>(payload: &str) -> bool {
+    serde_json::from_str::<T>(payload).is_ok()
+}
+
+// Check a payload decodes and passes the checks the receiver makes before acting on it
+fn accepts<T: DeserializeOwned>(payload: &str, check: impl Fn(&T) -> bool) -> bool {
+    serde_json::from_str::<T>(payload).is_ok_and(|request| check(&request))
+}
+
+// Parse a text frame, panicking if the parts handed to receivers do not add up to the frame
+pub fn parse_frame(message: &str) {
+    match validate::parse_frame(message) {
+        Frame::Request { receiver_id, payload } => {
+            // The receiver id and payload must be the two halves of the frame
+            assert!(receiver_id.len() <= validate::MAX_RECEIVER_ID_LEN);
+            assert_eq!(message.len(), receiver_id.len() + 1 + payload.len());
+
+            // Receivers check join codes and topics from the payload
+            if validate::valid_join_code(payload) {
+                assert!(payload.len() <= validate::MAX_JOIN_CODE_LEN);
+            }
+            let topics: Vec<String> = payload.split(',').map(str::to_string).collect();
+            if validate::check_topics(&topics).is_ok() {
+                assert!(topics.len() <= validate::MAX_TOPICS);
+            }
+        }
+        Frame::Ping | Frame::Invalid(_) => {}
+    }
+}
+
+// Parse a text frame and decode its payload as the receiver registered under its id would
+// Returns None for frames no receiver decodes, otherwise whether the payload was accepted
+pub fn decode_frame(message: &str) -> Option<bool> {
+    let (receiver_id, payload) = match validate::parse_frame(message) {
+        Frame::Request { receiver_id, payload } => (receiver_id, payload),
+        Frame::Ping | Frame::Invalid(_) => return None,
+    };
+
+    let decoded = match receiver_id {
+        "topic_request" => accepts(payload, |r: &TopicsRequest| r.check().is_ok()),
+        "join_station" => JoinRequest::parse(payload).is_some_and(|r| validate::valid_join_code(&r.code)),
+        "station_manage" => accepts(payload, |r: &ManageRequest| match r {
+            ManageRequest::Create { name, .. } | ManageRequest::Rename { name, .. } => lifecycle::valid_name(name).is_ok(),
+            _ => true,
+        }),
+        "station_chat" => accepts(payload, |r: &ChatRequest| r.text().is_some()),
+        "chat_moderate" => accepts(payload, |r: &ModerationRequest| r.check().is_ok()),
+        "vote_skip" => decodes::<SkipVoteRequest>(payload),
+        "vote_media" => decodes::<MediaVoteRequest>(payload),
+        "queue" => decodes::<QueueRequest>(payload),
+        "queue_edit" => accepts(payload, |r: &QueueEdit| match r {
+            QueueEdit::Add { media, .. } => media.check_trim().is_ok(),
+            _ => true,
+        }),
+        "station_role" => decodes::<RoleRequest>(payload),
+        "history" => decodes::<HistoryRequest>(payload),
+        "play_again" => decodes::<PlayAgainRequest>(payload),
+        "time_sync" => decodes::<TimeSyncRequest>(payload),
+        _ => return None,
+    };
+
+    Some(decoded)
+}
+
+// Decode a json body as every REST route taking one would
+pub fn decode_bodies(body: &str) {
+    decodes::<RegisterRequest>(body);
+    decodes::<CreateStationRequest>(body);
+    decodes::<RenameStationRequest>(body);
+    decodes::<RotateCodeRequest>(body);
+    decodes::<VisibilityRequest>(body);
+    decodes::<CapacityRequest>(body);
+}
@@ -1,15 +1,31 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use uuid::Uuid;
 use warp::http::StatusCode;
 use warp::Reply;
+use warp::hyper::body::Bytes;
 use warp::reply::{json};
-use warp::ws::Message;
-use crate::{Client, Clients, Receivers, Result, ws};
+use crate::{Client, Clients, Deliveries, Limiter, Receivers, Registrations, Result, ws};
+use crate::envelope::{BinaryPayload, Envelope, Payload};
+use crate::history::{self, Record, ReplayBuffer};
+use crate::identity::Identity;
+use crate::publishers::{self, ApiKey};
+use crate::redis_direct::get_con;
+use crate::registration::RegistrationTracker;
+use crate::station::StationMode;
+use crate::timer::unix_millis;
+use crate::validate;
 
 
+// Who the user is comes from their token, the body only holds preferences
 #[derive(Deserialize, Debug)]
 pub struct RegisterRequest {
-    user_id: usize,
+    // Name shown to other listeners, defaults to the name in the token, then the username
+    display_name: Option<String>,
+    // Whether joining a station leaves the previous one, the server default is used when missing
+    station_mode: Option<StationMode>,
 }
 
 #[derive(Serialize, Debug)]
@@ -19,62 +35,179 @@ pub struct RegisterResponse {
 
 #[derive(Deserialize, Debug)]
 pub struct Event {
+    // Optional message id, messages with an id are retried until acknowledged
+    id: Option<String>,
     topic: String,
     user_id: Option<usize>,
-    message: String,
+    // Any json value, sent to clients as a text frame
+    message: Option<Value>,
+    // Base64 encoded data, sent to clients as a binary frame
+    binary: Option<BinaryPayload>,
 }
 
+#[derive(Serialize, Debug)]
+pub struct PublishResponse {
+    // Amount of connections the message was handed to
+    delivered: usize,
+    // Amount of users the message is addressed to
+    recipients: usize,
+}
+
+pub async fn publish_handler(key: ApiKey, body: Bytes, addr: Option<SocketAddr>, clients: Clients, deliveries: Deliveries, redis_client: redis::Client) -> Result<impl Reply> {
+    // The body is read as bytes so its signature can be checked
+    let body: Event = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(_) => return Ok(StatusCode::BAD_REQUEST.into_response()),
+    };
+
+    // Keys may be limited to some topics and users
+    if !key.allows(&body.topic, body.user_id) {
+        publishers::log_rejected(Some(&key.id), addr, "topic or user out of scope");
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
+    // Clients can not listen to topics with other names
+    if !validate::valid_topic(&body.topic) {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    let payload = match (body.message, body.binary) {
+        (Some(message), None) => Payload::Json(message),
+        // Ensure binary data can be decoded before it is stored
+        (None, Some(binary)) if binary.decode().is_some() => Payload::Binary(binary),
+        // Exactly one valid kind of payload must be provided
+        _ => return Ok(StatusCode::BAD_REQUEST.into_response()),
+    };
+    let record = Record {
+        id: body.id,
+        user_id: body.user_id,
+        timestamp: unix_millis(),
+        payload,
+    };
+
+    // Append the message to the history of the topic
+    let stream_id = match get_con(redis_client).await {
+        Ok(mut con) => match history::append(&mut con, &body.topic, &record).await {
+            Ok(v) => Some(v),
+            Err(e) => {
+                eprintln!("could not append to topic history: {}", e);
+                None
+            }
+        },
+        Err(_) => {
+            eprintln!("could not connect to redis");
+            None
+        }
+    };
+
+    // Build the frame once so every client receives the same envelope
+    let message_id = record.id.as_deref();
+    let message = match (Envelope {
+        id: message_id,
+        stream_id: stream_id.as_deref(),
+        topic: &body.topic,
+        timestamp: record.timestamp,
+    }).message(&record.payload) {
+        Some(v) => v,
+        None => return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response()),
+    };
+
+    let mut delivered = 0;
+    let mut recipients = HashSet::new();
+    let mut sent_to = HashSet::new();
 
-pub async fn publish_handler(body: Event, clients: Clients) -> Result<impl Reply> {
-    clients
-        // Obtain read lock of client
-        .read()
-        .await
+    // Only lock the clients once the message is stored, subscribers replaying the history hold back live messages
+    // and drop the ones they already got from the history
+    clients.read().await
         // Create an iterator
         .iter()
         // Ensure user id matches with provided input
-        .filter(|(_, client)| match body.user_id {
+        .filter(|(_, client)| match record.user_id {
             Some(v) => client.user_id == v,
             None => true
         })
         // Filter out clients without provided topics
         .filter(|(_, client)| client.topics.contains(&body.topic))
         .for_each(|(_, client)| {
+            recipients.insert(client.user_id);
             if let Some(sender) = &client.sender {
-                // Send a message to clients which met filters
-                let _ = sender.send(Ok(Message::text(body.message.clone())));
+                // Send a message to clients which met filters, unless they are replaying the history
+                if client.replay.hold(&body.topic, stream_id.as_deref(), &message) || sender.send(Ok(message.clone())).is_ok() {
+                    delivered += 1;
+                    sent_to.insert(client.user_id);
+                }
             }
         });
 
-    Ok(StatusCode::OK)
+    // A message addressed to a user is kept for them even when they are offline
+    if let Some(user_id) = record.user_id {
+        recipients.insert(user_id);
+    }
+
+    // Keep messages with an id around until every recipient acknowledges them
+    if let Some(message_id) = message_id {
+        deliveries.track(message_id, &body.topic, record.user_id, &message, &recipients, &sent_to).await;
+    }
+
+    Ok(json(&PublishResponse {
+        delivered,
+        recipients: recipients.len(),
+    }).into_response())
+}
+
+pub async fn delivery_status_handler(id: String, key: ApiKey, deliveries: Deliveries) -> Result<impl Reply> {
+    // Return which recipients acknowledged the message, keys can not look at messages outside their scope
+    match deliveries.status(&id).await {
+        Some(v) if key.allows(&v.topic, v.user_id) => Ok(json(&v)),
+        _ => Err(warp::reject::not_found()),
+    }
 }
 
-pub async fn register_handler(body: RegisterRequest, clients: Clients) -> Result<impl Reply> {
-    let user_id = body.user_id;
+pub async fn register_handler(identity: Identity, body: RegisterRequest, clients: Clients, registrations: Registrations, default_mode: StationMode, ws_base_url: String) -> Result<impl Reply> {
+    let user_id = identity.user_id;
+    let display_name = body.display_name
+        .or(identity.display_name)
+        .unwrap_or_else(|| identity.username.clone());
+    let station_mode = body.station_mode.unwrap_or(default_mode);
     // Create UUID for connection
     let uuid = Uuid::new_v4().as_simple().to_string();
 
-    // Add client ot client list
-    register_client(uuid.clone(), user_id, clients).await;
+    // Add client ot client list, unless the user has too many registrations without a websocket
+    if !register_client(uuid.clone(), user_id, display_name, identity.username, station_mode, clients, &registrations).await {
+        return Ok(StatusCode::TOO_MANY_REQUESTS.into_response());
+    }
     // Return join link to client
     Ok(json(&RegisterResponse {
-        url: format!("ws://127.0.0.1:8000/ws/{}", uuid)
-    }))
+        url: ws_base_url + &uuid
+    }).into_response())
 }
 
-async fn register_client(id: String, user_id: usize, clients: Clients) {
-    // Get client lock and insert a client
-    clients.write().await.insert(
+async fn register_client(id: String, user_id: usize, display_name: String, username: String, station_mode: StationMode, clients: Clients, registrations: &RegistrationTracker) -> bool {
+    // Get client lock, holding it while counting so concurrent registrations can not pass the limit together
+    let mut clients_lock = clients.write().await;
+    if !registrations.has_room(&clients_lock, user_id) {
+        return false;
+    }
+
+    // Insert a client
+    clients_lock.insert(
         // Make the connection uuid the key
         id,
         Client {
             user_id,
+            display_name,
+            username,
+            station_mode,
             // Create a list with a default value
             topics: vec![String::from("default")],
+            replay: ReplayBuffer::default(),
             // Placeholder value for sender until client connects to websocket
             sender: None,
+            registered_at: unix_millis(),
         },
     );
+
+    true
 }
 
 pub async fn unregister_handler(id: String, clients: Clients) -> Result<impl Reply> {
@@ -84,12 +217,14 @@ pub async fn unregister_handler(id: String, clients: Clients) -> Result<impl Rep
     Ok(StatusCode::OK)
 }
 
-pub async fn ws_handler(ws: warp::ws::Ws, id: String, clients: Clients, redis_client: redis::Client, receiver_manger: Receivers) -> Result<impl Reply> {
-    // Get the client
-    let client = clients.read().await.get(&id).cloned();
+#[allow(clippy::too_many_arguments)]
+pub async fn ws_handler(ws: warp::ws::Ws, id: String, addr: Option<SocketAddr>, clients: Clients, redis_client: redis::Client, receiver_manger: Receivers, deliveries: Deliveries, limiter: Limiter, registrations: Registrations) -> Result<impl Reply> {
+    // Get the client, a registration which waited too long is gone even if the sweeper has not removed it yet
+    let client = clients.read().await.get(&id).filter(|c| !registrations.is_expired(c, unix_millis())).cloned();
+    let ip = addr.map(|a| a.ip());
     match client {
         // Attach a sender to client when the client joins the websocket
-        Some(c) => Ok(ws.on_upgrade(move |socket| ws::client_connection(socket, id, clients, c, redis_client, receiver_manger))),
+        Some(c) => Ok(ws.on_upgrade(move |socket| ws::client_connection(socket, id, clients, c, redis_client, receiver_manger, deliveries, limiter, ip))),
         // Return an error if it is a failure
         None => Err(warp::reject::not_found()),
     }
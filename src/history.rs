@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use redis::aio::Connection;
+use warp::ws::Message;
+use crate::DirectError::RedisCMDError;
+use crate::envelope::{BinaryPayload, Envelope, Payload};
+use crate::redis_direct::Result;
+
+// Maximum amount of messages kept in the history of a topic
+const MAX_HISTORY_LEN: usize = 500;
+
+// Published event as stored in the history of a topic
+pub struct Record {
+    pub id: Option<String>,
+    pub user_id: Option<usize>,
+    pub timestamp: u64,
+    pub payload: Payload,
+}
+
+// Which part of the history a subscriber asked for
+pub enum Replay {
+    // The last n messages
+    Last(usize),
+    // Every message after the given stream id
+    Since(String),
+}
+
+impl Record {
+    // Create the frame sent to clients for this record
+    pub fn message(&self, stream_id: &str, topic: &str) -> Option<Message> {
+        Envelope {
+            id: self.id.as_deref(),
+            stream_id: Some(stream_id),
+            topic,
+            timestamp: self.timestamp,
+        }.message(&self.payload)
+    }
+
+    // Rebuild a record from the fields of a stream entry
+    fn from_fields(mut fields: HashMap<String, String>) -> Option<Record> {
+        let payload = match fields.remove("message") {
+            Some(v) => Payload::Json(serde_json::from_str(&v).ok()?),
+            None => Payload::Binary(BinaryPayload {
+                content_type: fields.remove("contentType")?,
+                data: fields.remove("data")?,
+            }),
+        };
+
+        Some(Record {
+            id: fields.remove("id"),
+            user_id: fields.get("userId").and_then(|v| v.parse().ok()),
+            timestamp: fields.get("timestamp").and_then(|v| v.parse().ok()).unwrap_or(0),
+            payload,
+        })
+    }
+}
+
+// Live message published while a client was replaying the history of its topic
+#[derive(Debug)]
+pub struct Live {
+    pub topic: String,
+    pub stream_id: Option<String>,
+    pub message: Message,
+}
+
+// Live messages held back from a client while its history is loaded
+// Publishers only hold a read lock on the clients, so the buffer needs its own lock
+#[derive(Debug, Clone, Default)]
+pub struct ReplayBuffer(Arc<Mutex<Option<Vec<Live>>>>);
+
+impl ReplayBuffer {
+    // Start holding back live messages, messages already held back are kept
+    pub fn start(&self) {
+        if let Ok(mut buffer) = self.0.lock() {
+            buffer.get_or_insert_with(Vec::new);
+        }
+    }
+
+    // Hold back a message if a replay is running, returns false if the message should be sent right away
+    pub fn hold(&self, topic: &str, stream_id: Option<&str>, message: &Message) -> bool {
+        match self.0.lock() {
+            Ok(mut buffer) => match buffer.as_mut() {
+                Some(held) => {
+                    held.push(Live {
+                        topic: topic.to_string(),
+                        stream_id: stream_id.map(str::to_string),
+                        message: message.clone(),
+                    });
+                    true
+                }
+                None => false,
+            },
+            Err(_) => false,
+        }
+    }
+
+    // Stop holding back messages, returning the held messages ordered by stream id
+    pub fn finish(&self) -> Vec<Live> {
+        let mut held = match self.0.lock() {
+            Ok(mut buffer) => buffer.take().unwrap_or_default(),
+            Err(_) => return Vec::new(),
+        };
+
+        // Publishers may take the lock in another order than they appended to the stream
+        held.sort_by_key(|l| l.stream_id.as_deref().and_then(parse_stream_id));
+        held
+    }
+}
+
+// Split a stream id into its time and sequence part so ids can be compared
+pub fn parse_stream_id(stream_id: &str) -> Option<(u64, u64)> {
+    let (time, sequence) = stream_id.split_once('-')?;
+    Some((time.parse().ok()?, sequence.parse().ok()?))
+}
+
+// Check if a stream id comes after another one
+pub fn is_after(stream_id: &str, other: &str) -> bool {
+    match (parse_stream_id(stream_id), parse_stream_id(other)) {
+        (Some(a), Some(b)) => a > b,
+        // Ids which can not be compared are treated as new so no message is lost
+        _ => true,
+    }
+}
+
+// Construct the redis key of the history stream for a topic
+fn history_key(topic: &str) -> String {
+    "topic-history:".to_owned() + topic
+}
+
+// Append a record to the history of a topic and return its stream id
+pub async fn append(con: &mut Connection, topic: &str, record: &Record) -> Result<String> {
+    // Create add command, trimming the stream to roughly the max length
+    let mut cmd = redis::cmd("XADD");
+    cmd.arg(history_key(topic))
+        .arg("MAXLEN")
+        .arg("~")
+        .arg(MAX_HISTORY_LEN)
+        .arg("*")
+        .arg("timestamp")
+        .arg(record.timestamp);
+
+    // Add optional fields
+    if let Some(id) = &record.id {
+        cmd.arg("id").arg(id);
+    }
+    if let Some(user_id) = record.user_id {
+        cmd.arg("userId").arg(user_id);
+    }
+
+    // Add the payload
+    match &record.payload {
+        Payload::Json(message) => {
+            cmd.arg("message").arg(message.to_string());
+        }
+        Payload::Binary(binary) => {
+            cmd.arg("contentType").arg(&binary.content_type).arg("data").arg(&binary.data);
+        }
+    }
+
+    cmd.query_async(con).await.map_err(|e| RedisCMDError(e).into())
+}
+
+// Load part of the history of a topic, oldest message first
+pub async fn load(con: &mut Connection, topic: &str, replay: &Replay) -> Result<Vec<(String, Record)>> {
+    let key = history_key(topic);
+
+    let entries: Vec<(String, HashMap<String, String>)> = match replay {
+        Replay::Last(count) => {
+            // Read backwards from the newest message
+            let mut entries: Vec<(String, HashMap<String, String>)> = redis::cmd("XREVRANGE")
+                .arg(&key)
+                .arg("+")
+                .arg("-")
+                .arg("COUNT")
+                .arg((*count).min(MAX_HISTORY_LEN))
+                .query_async(con)
+                .await
+                .map_err(RedisCMDError)?;
+            entries.reverse();
+
+            entries
+        }
+        Replay::Since(stream_id) => {
+            // Range is inclusive so drop the message the client already has
+            let entries: Vec<(String, HashMap<String, String>)> = redis::cmd("XRANGE")
+                .arg(&key)
+                .arg(stream_id)
+                .arg("+")
+                .arg("COUNT")
+                .arg(MAX_HISTORY_LEN + 1)
+                .query_async(con)
+                .await
+                .map_err(RedisCMDError)?;
+
+            entries.into_iter().filter(|(id, _)| id != stream_id).collect()
+        }
+    };
+
+    // Skip entries which could not be read
+    Ok(entries.into_iter()
+        .filter_map(|(id, fields)| Record::from_fields(fields).map(|r| (id, r)))
+        .collect())
+}
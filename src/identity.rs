@@ -0,0 +1,148 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use warp::{Filter, Rejection, Reply};
+use warp::http::StatusCode;
+use warp::http::header::AUTHORIZATION;
+use crate::timer::unix_millis;
+
+// User a token was issued for by the backend
+#[derive(Deserialize, Debug, Clone)]
+pub struct Identity {
+    #[serde(rename = "userId")]
+    pub user_id: usize,
+    // Account name, used to check station ownership
+    #[serde(rename = "username")]
+    pub username: String,
+    // Name shown to other listeners, the client may pick another one when registering
+    #[serde(rename = "displayName")]
+    pub display_name: Option<String>,
+    // Unix time in seconds the token stops working
+    #[serde(rename = "exp")]
+    expires_at: u64,
+}
+
+// Checks user tokens issued by the backend with a shared secret
+// A token is <base64url claims json>.<base64url HMAC-SHA256 of the encoded claims>
+#[derive(Clone)]
+pub struct TokenVerifier {
+    secret: String,
+}
+
+impl TokenVerifier {
+    // Boilerplate for creating a new instance
+    pub fn new(secret: String) -> TokenVerifier {
+        TokenVerifier { secret }
+    }
+
+    // Check the signature and expiry of a token, returning who it was issued for
+    pub fn verify(&self, token: &str) -> Result<Identity, &'static str> {
+        let (claims, signature) = token.split_once('.').ok_or("malformed token")?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| "malformed signature")?;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes()).map_err(|_| "invalid secret")?;
+        mac.update(claims.as_bytes());
+        mac.verify_slice(&signature).map_err(|_| "wrong signature")?;
+
+        // Only read the claims once they are known to come from the backend
+        let claims = URL_SAFE_NO_PAD.decode(claims).map_err(|_| "malformed claims")?;
+        let identity: Identity = serde_json::from_slice(&claims).map_err(|_| "malformed claims")?;
+        if identity.expires_at <= unix_millis() / 1000 {
+            return Err("token expired");
+        }
+
+        Ok(identity)
+    }
+}
+
+// Rejection for requests without a valid user token
+#[derive(Debug)]
+struct Unauthenticated;
+impl warp::reject::Reject for Unauthenticated {}
+
+// Filter extracting the user a request was made by from its bearer token
+pub fn authenticated(verifier: TokenVerifier) -> impl Filter<Extract = (Identity,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(AUTHORIZATION.as_str())
+        .and_then(move |authorization: Option<String>| {
+            let result = authorization.as_deref()
+                .and_then(|a| a.strip_prefix("Bearer "))
+                .ok_or("missing token")
+                .and_then(|token| verifier.verify(token.trim()));
+
+            async move {
+                result.map_err(|reason| {
+                    eprintln!("request rejected: {}", reason);
+                    warp::reject::custom(Unauthenticated)
+                })
+            }
+        })
+}
+
+// Answer requests without a valid user token with 401, leaving other rejections to warp
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<Unauthenticated>().is_some() {
+        return Ok(StatusCode::UNAUTHORIZED);
+    }
+
+    Err(rejection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test-secret";
+
+    // Sign claims the way the backend does
+    fn token(claims: &str, secret: &str) -> String {
+        let claims = URL_SAFE_NO_PAD.encode(claims);
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes any key");
+        mac.update(claims.as_bytes());
+        format!("{}.{}", claims, URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()))
+    }
+
+    // Claims of a user whose token stops working at the given unix time in seconds
+    fn claims(expires_at: u64) -> String {
+        format!(r#"{{"userId":1,"username":"test","exp":{}}}"#, expires_at)
+    }
+
+    #[test]
+    fn verify() {
+        let verifier = TokenVerifier::new(SECRET.to_string());
+        let future = unix_millis() / 1000 + 60;
+        let valid = token(&claims(future), SECRET);
+        let (valid_claims, valid_signature) = valid.split_once('.').expect("tokens have two parts");
+
+        let cases = [
+            // (token, error)
+            (valid.clone(), None),
+            // Signed with another secret, a made up signature and claims changed after signing
+            (token(&claims(future), "other-secret"), Some("wrong signature")),
+            (format!("{}.{}", valid_claims, URL_SAFE_NO_PAD.encode([0u8; 32])), Some("wrong signature")),
+            (format!("{}.{}", URL_SAFE_NO_PAD.encode(claims(future + 1)), valid_signature), Some("wrong signature")),
+            (token(&claims(1), SECRET), Some("token expired")),
+            // Tokens which are not <claims>.<signature>
+            (token("not json", SECRET), Some("malformed claims")),
+            (valid_claims.to_string(), Some("malformed token")),
+            (format!("{}.not base64!", valid_claims), Some("malformed signature")),
+            (String::new(), Some("malformed token")),
+        ];
+
+        for (token, error) in cases {
+            let result = verifier.verify(&token);
+            assert_eq!(result.as_ref().err().copied(), error, "{}", token);
+        }
+    }
+
+    #[test]
+    fn verify_reads_claims() {
+        let verifier = TokenVerifier::new(SECRET.to_string());
+        let identity = verifier.verify(&token(&claims(unix_millis() / 1000 + 60), SECRET)).expect("token is valid");
+
+        assert_eq!(identity.user_id, 1);
+        assert_eq!(identity.username, "test");
+        assert_eq!(identity.display_name, None);
+    }
+}
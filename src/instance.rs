@@ -0,0 +1,81 @@
+use std::collections::HashSet;
+use std::sync::OnceLock;
+use std::time::Duration;
+use redis::aio::Connection;
+use tokio::time;
+use uuid::Uuid;
+use crate::DirectError::RedisCMDError;
+use crate::redis_direct::{get_con, Result};
+
+// Prefix of the redis keys holding instance heartbeats
+pub const HEARTBEAT_KEY_PREFIX: &str = "vradio-instance:";
+// Seconds an instance counts as alive after its last heartbeat
+const HEARTBEAT_TTL_SECS: u64 = 30;
+// Seconds between heartbeats, well below the ttl so one slow beat does not look like a crash
+const HEARTBEAT_INTERVAL_SECS: u64 = 10;
+
+static INSTANCE_ID: OnceLock<String> = OnceLock::new();
+
+// Id of this instance, shared state is tagged with it so other instances can clean up after a crash
+pub fn id() -> &'static str {
+    INSTANCE_ID.get_or_init(|| Uuid::new_v4().simple().to_string())
+}
+
+// Construct the redis key of the heartbeat of an instance
+fn heartbeat_key(id: &str) -> String {
+    HEARTBEAT_KEY_PREFIX.to_owned() + id
+}
+
+// Mark this instance as alive for a while
+pub async fn beat(con: &mut Connection) -> Result<()> {
+    redis::cmd("SET")
+        .arg(heartbeat_key(id()))
+        .arg(1)
+        .arg("EX")
+        .arg(HEARTBEAT_TTL_SECS)
+        .query_async::<_, ()>(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    Ok(())
+}
+
+// Find which of the given instances are still alive, this instance always is
+pub async fn alive(con: &mut Connection, ids: &HashSet<&str>) -> Result<HashSet<String>> {
+    let others: Vec<&str> = ids.iter().copied().filter(|i| *i != id()).collect();
+    let mut alive: HashSet<String> = ids.iter().filter(|i| **i == id()).map(|i| i.to_string()).collect();
+    if others.is_empty() {
+        return Ok(alive);
+    }
+
+    let beats: Vec<Option<String>> = redis::cmd("MGET")
+        .arg(others.iter().map(|i| heartbeat_key(i)).collect::<Vec<_>>())
+        .query_async(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    alive.extend(others.iter().zip(beats).filter(|(_, b)| b.is_some()).map(|(i, _)| i.to_string()));
+    Ok(alive)
+}
+
+// Keep the heartbeat of this instance fresh forever
+pub async fn heartbeat(redis_client: redis::Client) {
+    let mut interval = time::interval(Duration::from_secs(HEARTBEAT_INTERVAL_SECS));
+
+    loop {
+        interval.tick().await;
+
+        // Establish connection to redis
+        let mut redis_con = match get_con(redis_client.clone()).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis");
+                continue;
+            }
+        };
+
+        if let Err(e) = beat(&mut redis_con).await {
+            eprintln!("could not send instance heartbeat: {}", e);
+        }
+    }
+}
@@ -0,0 +1,480 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc};
+use std::time::Duration;
+use tokio::sync::{mpsc, RwLock};
+use warp::{Filter, Rejection};
+use warp::ws::{Message, Ws};
+use thiserror::Error;
+use uuid::Uuid;
+use tokio::time;
+use crate::chat::{ChatReceiver, ModerationReceiver};
+use crate::clock::TimeSyncReceiver;
+use crate::config::Config;
+use crate::delivery::{AckReceiver, DeliveryTracker};
+use crate::history::ReplayBuffer;
+use crate::identity::TokenVerifier;
+use crate::lifecycle::ManageReceiver;
+use crate::message_receive::{Receiver, ReceiverManager};
+use crate::played::{HistoryReceiver, PlayAgainReceiver};
+use crate::presence::{Listener, ListenersReceiver};
+use crate::publishers::Authenticator;
+use crate::queue::{QueueEditReceiver, QueueReceiver};
+use crate::ratelimit::RateLimiter;
+use crate::registration::RegistrationTracker;
+use crate::roles::RoleReceiver;
+use crate::station::{MembershipsReceiver, StationManager, StationMode};
+use crate::tls::ReloadingCert;
+use crate::votes::{MediaVoteReceiver, SkipVoteReceiver};
+use crate::ws::TopicRequestReceiver;
+
+mod access;
+mod changes;
+mod chat;
+mod clock;
+mod config;
+mod delivery;
+mod envelope;
+mod fanout;
+mod handler;
+mod history;
+mod identity;
+mod instance;
+mod lifecycle;
+mod ws;
+mod message_receive;
+mod origins;
+mod played;
+mod presence;
+mod publishers;
+mod queue;
+mod ratelimit;
+mod redis_direct;
+mod registration;
+mod roles;
+mod station;
+mod timer;
+mod tls;
+mod validate;
+mod votes;
+mod waiting;
+
+// Decoders the fuzz targets in fuzz/ drive, not part of the server
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+
+type Result<T> = std::result::Result<T, Rejection>;
+type Clients = Arc<RwLock<HashMap<String, Client>>>;
+type Receivers = Arc<ReceiverManager>;
+type Deliveries = Arc<DeliveryTracker>;
+type Limiter = Arc<RateLimiter>;
+type Registrations = Arc<RegistrationTracker>;
+
+const REDIS_CON_STRING: &str = "redis://127.0.0.1/";
+// Seconds between attempts to resend unacknowledged messages
+const RETRY_INTERVAL_SECS: u64 = 15;
+// Seconds between dropping idle rate limit buckets
+const RATE_LIMIT_PRUNE_SECS: u64 = 60;
+// Seconds between removing registrations which never opened a websocket
+const REGISTRATION_SWEEP_SECS: u64 = 15;
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub user_id: usize,
+    // Name shown to other listeners of a station
+    pub display_name: String,
+    // Account name from the user token, used to check station ownership
+    pub username: String,
+    // Whether joining a station leaves the previous one
+    pub station_mode: StationMode,
+    // Time of registering in unix milliseconds, used to expire registrations without a websocket
+    pub registered_at: u64,
+    pub topics: Vec<String>,
+    // Live messages held back while the history of a topic is replayed
+    pub replay: ReplayBuffer,
+    pub sender: Option<mpsc::UnboundedSender<std::result::Result<Message, warp::Error>>>
+}
+
+impl Client {
+    // Describe the client as a station listener
+    pub fn listener(&self) -> Listener {
+        Listener {
+            user_id: self.user_id,
+            display_name: self.display_name.clone(),
+        }
+    }
+}
+
+// Start the server and serve until the process is stopped
+pub async fn run() {
+    // Load settings
+    let config = Config::from_env();
+
+    // Register clients list
+    let clients: Clients = Arc::new(RwLock::new(HashMap::new()));
+
+    // Create client
+    let redis_client = redis::Client::open(REDIS_CON_STRING).expect("can create redis client");
+
+    // Create map of receivers
+    let mut receiver_map: HashMap<String, Arc<dyn Receiver>> = HashMap::new();
+    // Create the station manager list
+    let stations = Arc::new(StationManager::new(config.max_listeners));
+    // Clone the arc to allow safe moving between threads
+    let stations_clone = stations.clone();
+    // Create the tracker for messages awaiting acknowledgement
+    let deliveries: Deliveries = Arc::new(DeliveryTracker::new());
+    // Create the rate limiter shared by websocket frames and HTTP routes
+    let limiter: Limiter = Arc::new(RateLimiter::new(config.rate_limits.clone(), config.rate_limit_strikes));
+    // Create the tracker expiring registrations which never open a websocket
+    let registrations: Registrations = Arc::new(RegistrationTracker::new(config.registration_ttl_secs, config.max_pending_registrations));
+
+    // Add the receivers
+    receiver_map.insert("topic_request".to_string(), Arc::new(TopicRequestReceiver {}));
+    receiver_map.insert("join_station".to_string(), stations);
+    receiver_map.insert("station_manage".to_string(), Arc::new(ManageReceiver { stations: stations_clone.clone(), join_code_ttl_secs: config.join_code_ttl_secs }));
+    receiver_map.insert("memberships".to_string(), Arc::new(MembershipsReceiver { stations: stations_clone.clone() }));
+    receiver_map.insert("listeners".to_string(), Arc::new(ListenersReceiver { stations: stations_clone.clone() }));
+    receiver_map.insert("station_chat".to_string(), Arc::new(ChatReceiver { stations: stations_clone.clone() }));
+    receiver_map.insert("chat_moderate".to_string(), Arc::new(ModerationReceiver { stations: stations_clone.clone() }));
+    receiver_map.insert("vote_skip".to_string(), Arc::new(SkipVoteReceiver { stations: stations_clone.clone(), threshold: config.skip_threshold }));
+    receiver_map.insert("queue".to_string(), Arc::new(QueueReceiver { stations: stations_clone.clone() }));
+    receiver_map.insert("queue_edit".to_string(), Arc::new(QueueEditReceiver { stations: stations_clone.clone() }));
+    receiver_map.insert("station_role".to_string(), Arc::new(RoleReceiver { stations: stations_clone.clone() }));
+    receiver_map.insert("history".to_string(), Arc::new(HistoryReceiver { stations: stations_clone.clone() }));
+    receiver_map.insert("play_again".to_string(), Arc::new(PlayAgainReceiver { stations: stations_clone.clone() }));
+    receiver_map.insert("vote_media".to_string(), Arc::new(MediaVoteReceiver { stations: stations_clone.clone(), threshold: config.skip_threshold }));
+    receiver_map.insert("time_sync".to_string(), Arc::new(TimeSyncReceiver {}));
+    receiver_map.insert("ack".to_string(), Arc::new(AckReceiver { deliveries: deliveries.clone() }));
+
+    // Wrap receivers in an arc to allow safe movement between threads
+    let receiver_manager: Receivers = Arc::new(ReceiverManager {receivers: receiver_map});
+
+    // Add health handler to ensure websocket server is started
+    let health_route = warp::path!("health").and_then(handler::health_handler);
+
+    // Add route reporting counters to monitoring
+    let metrics_route = warp::path!("metrics")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(tls::remote())
+        .and(with_metrics_token(config.metrics_token.clone()))
+        .and(with_clients(clients.clone()))
+        .and(with_registrations(registrations.clone()))
+        .and_then(registration::metrics_handler);
+
+
+    // Users prove who they are with a token signed by the backend
+    let tokens = TokenVerifier::new(config.user_token_secret.clone());
+
+    // Add route to register and delete clients
+    let register = warp::path("register");
+    let register_routes = register
+        .and(warp::post())
+        .and(ratelimit::limit(limiter.clone(), "register"))
+        .and(identity::authenticated(tokens.clone()))
+        .and(warp::body::json())
+        .and(with_clients(clients.clone()))
+        .and(with_registrations(registrations.clone()))
+        .and(with_station_mode(config.station_mode))
+        .and(with_ws_base_url(config.ws_base_url()))
+        .and_then(handler::register_handler)
+        .or(register
+            .and(warp::delete())
+            .and(warp::path::param())
+            .and(with_clients(clients.clone()))
+            .and_then(handler::unregister_handler));
+
+    // Publishers authenticate with a key from the config or redis
+    let authenticator = Authenticator {
+        enabled: config.publish_auth,
+        keys: config.publish_keys.clone(),
+        redis_client: redis_client.clone(),
+    };
+    if !config.publish_auth {
+        eprintln!("publish authentication is turned off, anyone can publish");
+    }
+
+    // Add route to publish a message to the websocket server
+    let publish = warp::path!("publish")
+        .and(ratelimit::limit(limiter.clone(), "publish"))
+        .and(publishers::authenticate(authenticator.clone()))
+        .and(tls::remote())
+        .and(with_clients(clients.clone()))
+        .and(with_deliveries(deliveries.clone()))
+        .and(with_redis_client(redis_client.clone()))
+        .and_then(handler::publish_handler)
+        // Add route to query which users acknowledged a message
+        .or(warp::path!("publish" / String)
+            .and(warp::get())
+            .and(publishers::authorized(authenticator))
+            .and(with_deliveries(deliveries.clone()))
+            .and_then(handler::delivery_status_handler));
+
+    // Add route to get the recently played media of a station
+    let station_history = warp::path!("stations" / Uuid / "history")
+        .and(warp::get())
+        .and(identity::authenticated(tokens.clone()))
+        .and(warp::header::optional::<String>(access::PASSWORD_HEADER))
+        .and(warp::query())
+        .and(with_redis_client(redis_client.clone()))
+        .and_then(played::history_handler);
+
+    // Add routes to create, rename and delete stations and manage their join codes
+    // Every route needs a user token, changes are only made by the owner of the station
+    let authenticated = identity::authenticated(tokens.clone());
+    let station_routes = warp::path!("stations")
+        .and(warp::post())
+        .and(authenticated.clone())
+        .and(warp::body::json())
+        .and(with_redis_client(redis_client.clone()))
+        .and(with_join_code_ttl(config.join_code_ttl_secs))
+        .and_then(lifecycle::create_handler)
+        .or(warp::path!("stations" / Uuid)
+            .and(warp::patch())
+            .and(warp::body::json())
+            .and(authenticated.clone())
+            .and(with_redis_client(redis_client.clone()))
+            .and_then(lifecycle::rename_handler))
+        .or(warp::path!("stations" / Uuid)
+            .and(warp::delete())
+            .and(authenticated.clone())
+            .and(with_redis_client(redis_client.clone()))
+            .and_then(lifecycle::delete_handler))
+        .or(warp::path!("stations" / Uuid / "join-code")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(authenticated.clone())
+            .and(with_redis_client(redis_client.clone()))
+            .and(with_join_code_ttl(config.join_code_ttl_secs))
+            .and_then(lifecycle::rotate_code_handler))
+        .or(warp::path!("stations" / Uuid / "join-code")
+            .and(warp::delete())
+            .and(authenticated.clone())
+            .and(with_redis_client(redis_client.clone()))
+            .and_then(lifecycle::revoke_code_handler))
+        // Add routes to list public stations and change who may join a station
+        .or(warp::path!("stations")
+            .and(warp::get())
+            .and(authenticated.clone())
+            .and(with_redis_client(redis_client.clone()))
+            .and_then(lifecycle::list_handler))
+        .or(warp::path!("stations" / Uuid / "visibility")
+            .and(warp::put())
+            .and(warp::body::json())
+            .and(authenticated.clone())
+            .and(with_redis_client(redis_client.clone()))
+            .and_then(lifecycle::visibility_handler))
+        .or(warp::path!("stations" / Uuid / "capacity")
+            .and(warp::put())
+            .and(warp::body::json())
+            .and(authenticated.clone())
+            .and(with_redis_client(redis_client.clone()))
+            .and_then(lifecycle::capacity_handler))
+        .or(warp::path!("stations" / Uuid / "invites" / usize)
+            .and(warp::put())
+            .map(|station_id, user_id| (station_id, user_id, true))
+            .untuple_one()
+            .and(authenticated.clone())
+            .and(with_redis_client(redis_client.clone()))
+            .and_then(lifecycle::invite_handler))
+        .or(warp::path!("stations" / Uuid / "invites" / usize)
+            .and(warp::delete())
+            .map(|station_id, user_id| (station_id, user_id, false))
+            .untuple_one()
+            .and(authenticated.clone())
+            .and(with_redis_client(redis_client.clone()))
+            .and_then(lifecycle::invite_handler));
+
+    // Add route to join the web socket
+    let ws_route = warp::path("ws")
+        .and(origins::check(config.allowed_origins.clone()))
+        .and(with_ws_limits(config.max_frame_bytes, config.max_message_bytes))
+        .and(warp::path::param())
+        .and(tls::remote())
+        .and(with_clients(clients.clone()))
+        .and(with_redis_client(redis_client.clone()))
+        .and(with_receiver_manager(receiver_manager))
+        .and(with_deliveries(deliveries.clone()))
+        .and(with_limiter(limiter.clone()))
+        .and(with_registrations(registrations.clone()))
+        .and_then(handler::ws_handler);
+
+    // Register all REST routes
+    let rest_routes = health_route
+        .or(metrics_route)
+        .or(register_routes)
+        .or(publish)
+        .or(station_history)
+        .or(station_routes)
+        // Answer requests over their rate limit with 429
+        .recover(ratelimit::handle_rejection)
+        // Answer publishers without valid credentials with 401
+        .recover(publishers::handle_rejection)
+        // Answer users without a valid token with 401
+        .recover(identity::handle_rejection)
+        // Only let the allowed websites call the routes, others get a 403
+        .with(config.allowed_origins.cors());
+
+    // Websockets check the origin themselves since CORS does not cover them
+    let routes = ws_route
+        .or(rest_routes)
+        .recover(origins::handle_rejection);
+
+    // Spawn task reloading stations when they are written
+    tokio::spawn(changes::listen(stations_clone.clone(), clients.clone(), redis_client.clone(), config.keyspace_events));
+
+    // Spawn task telling other instances this one is alive
+    tokio::spawn(instance::heartbeat(redis_client.clone()));
+
+    // Spawn task handling station events from every instance
+    tokio::spawn(fanout::listen(stations_clone.clone(), clients.clone(), redis_client));
+
+    // Clone clients to allow it to move to the update task
+    let clients_clone = clients.clone();
+
+    // Spawn station update task
+    tokio::spawn(async move {
+        // Create interval to run task every 30 seconds
+        let mut interval = time::interval(Duration::from_secs(30));
+        // Create a new redis client
+        let new_client = redis::Client::open(REDIS_CON_STRING).expect("can create redis client");
+
+        loop {
+            // Ensure interval is reached
+            interval.tick().await;
+            // Update all clients on station status
+            stations_clone.update_clients(&clients_clone, new_client.clone()).await;
+        }
+    });
+
+    // Spawn task removing registrations which never opened a websocket
+    let sweep_clients = clients.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(REGISTRATION_SWEEP_SECS));
+
+        loop {
+            interval.tick().await;
+            registrations.sweep(&sweep_clients).await;
+        }
+    });
+
+    // Spawn task resending unacknowledged messages
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(RETRY_INTERVAL_SECS));
+
+        loop {
+            interval.tick().await;
+            deliveries.retry(&clients).await;
+        }
+    });
+
+    // Spawn task dropping rate limit state which is no longer needed
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(RATE_LIMIT_PRUNE_SECS));
+
+        loop {
+            interval.tick().await;
+            limiter.prune().await;
+        }
+    });
+
+    // Serve TLS when a certificate is configured
+    match (config.tls_cert_path, config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let cert = Arc::new(ReloadingCert::new(cert_path, key_path).expect("can load tls certificate"));
+
+            // Spawn task picking up renewed certificates
+            if config.tls_reload_secs > 0 {
+                tokio::spawn(cert.clone().watch(config.tls_reload_secs));
+            }
+
+            tls::serve(warp::service(routes), config.listen_addr, cert).await
+        }
+        (None, None) => warp::serve(routes).run(config.listen_addr).await,
+        _ => panic!("VRADIO_TLS_CERT and VRADIO_TLS_KEY must be set together"),
+    }
+}
+
+fn with_clients(clients: Clients) -> impl Filter<Extract = (Clients,), Error = Infallible> + Clone {
+    warp::any().map(move || clients.clone())
+}
+
+fn with_redis_client(client: redis::Client) -> impl Filter<Extract = (redis::Client,), Error = Infallible> + Clone {
+    warp::any().map(move || client.clone())
+}
+
+fn with_deliveries(deliveries: Deliveries) -> impl Filter<Extract = (Deliveries,), Error = Infallible> + Clone {
+    warp::any().map(move || deliveries.clone())
+}
+
+// Websocket upgrade which closes connections sending frames or messages over the size limits
+fn with_ws_limits(max_frame_bytes: usize, max_message_bytes: usize) -> impl Filter<Extract = (Ws,), Error = Rejection> + Clone {
+    warp::ws().map(move |ws: Ws| ws.max_frame_size(max_frame_bytes).max_message_size(max_message_bytes))
+}
+
+fn with_limiter(limiter: Limiter) -> impl Filter<Extract = (Limiter,), Error = Infallible> + Clone {
+    warp::any().map(move || limiter.clone())
+}
+
+fn with_registrations(registrations: Registrations) -> impl Filter<Extract = (Registrations,), Error = Infallible> + Clone {
+    warp::any().map(move || registrations.clone())
+}
+
+fn with_station_mode(mode: StationMode) -> impl Filter<Extract = (StationMode,), Error = Infallible> + Clone {
+    warp::any().map(move || mode)
+}
+
+fn with_metrics_token(token: Option<String>) -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Clone {
+    warp::any().map(move || token.clone())
+}
+
+fn with_ws_base_url(url: String) -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::any().map(move || url.clone())
+}
+
+fn with_join_code_ttl(ttl_secs: u64) -> impl Filter<Extract = (u64,), Error = Infallible> + Clone {
+    warp::any().map(move || ttl_secs)
+}
+
+fn with_receiver_manager(receivers: Receivers) -> impl Filter<Extract = (Receivers,), Error = Infallible> + Clone {
+    warp::any().map(move || receivers.clone())
+}
+
+#[derive(Error, Debug)]
+pub enum RedisError {
+    #[error("direct redis error: {0}")]
+    DirectError(#[from] DirectError)
+}
+
+#[derive(Error, Debug)]
+pub enum DirectError {
+    #[error("error paring string from redis_direct result: {0}")]
+    RedisTypeError(redis::RedisError),
+    #[error("error executing redis_direct command: {0}")]
+    RedisCMDError(redis::RedisError),
+    #[error("error creating Redis client: {0}")]
+    RedisClientError(redis::RedisError),
+}
+
+#[derive(Error, Debug)]
+pub enum StationError {
+    #[error("station {0} does not exist")]
+    NotFound(String),
+    #[error("could not load station {0}: {1}")]
+    Redis(String, RedisError),
+    #[error("could not decode station {0} at '{1}': {2}")]
+    Decode(String, String, serde_json::Error),
+    #[error("only the owner of station {0} can do that")]
+    NotOwner(String),
+    #[error("could not save station {0}")]
+    NotSaved(String),
+    #[error("station name must be between 1 and {0} characters")]
+    InvalidName(usize),
+    #[error("station password must be between 1 and {0} characters")]
+    InvalidPassword(usize),
+    #[error("could not find an unused join code")]
+    NoJoinCode,
+    #[error("could not hash the password of station {0}")]
+    NotHashed(String),
+}
@@ -0,0 +1,718 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use redis::aio::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::from_str;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::Reply;
+use warp::reply::{json, with_status};
+use crate::{changes, fanout, played, presence, votes, waiting, ws, Clients, StationError};
+use crate::DirectError::RedisCMDError;
+use crate::identity::Identity;
+use crate::message_receive::Receiver;
+use crate::redis_direct::get_con;
+use crate::access::{hash_password, MAX_PASSWORD_LEN};
+use crate::station::{from_redis, modify, to_redis, JoinCode, Station, StationManager, StationRef, Visibility};
+use crate::timer::unix_millis;
+
+// Characters join codes are made of, leaving out ones which are easily confused like 0 and O
+const JOIN_CODE_ALPHABET: &[u8; 32] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+// Amount of characters in a join code
+const JOIN_CODE_LEN: usize = 6;
+// Amount of codes tried before giving up on finding an unused one
+const JOIN_CODE_ATTEMPTS: usize = 10;
+// Join codes can not be made to expire sooner than this
+const MIN_JOIN_CODE_TTL_SECS: u64 = 60;
+// Maximum amount of characters in a station name
+const MAX_NAME_LEN: usize = 100;
+// Redis set holding the id of every public station
+const PUBLIC_STATIONS_KEY: &str = "public-stations";
+
+// Request to create a station over REST, owned by the user whose token made the request
+#[derive(Deserialize, Debug)]
+pub struct CreateStationRequest {
+    #[serde(rename = "name")]
+    name: String,
+    #[serde(rename = "codeTtlSecs")]
+    code_ttl_secs: Option<u64>,
+}
+
+// Request to rename a station over REST
+#[derive(Deserialize, Debug)]
+pub struct RenameStationRequest {
+    #[serde(rename = "name")]
+    name: String,
+}
+
+// Request to replace the join code of a station over REST
+#[derive(Deserialize, Debug)]
+pub struct RotateCodeRequest {
+    #[serde(rename = "codeTtlSecs")]
+    code_ttl_secs: Option<u64>,
+}
+
+// Request to change who may join a station over REST
+#[derive(Deserialize, Debug)]
+pub struct VisibilityRequest {
+    #[serde(rename = "visibility")]
+    visibility: Visibility,
+    // Needed when making a station password protected, unless it already has one
+    #[serde(rename = "password")]
+    password: Option<String>,
+}
+
+// Request to change the listener limit of a station over REST
+#[derive(Deserialize, Debug)]
+pub struct CapacityRequest {
+    // Missing uses the server default, 0 means no limit
+    #[serde(rename = "maxListeners")]
+    max_listeners: Option<usize>,
+}
+
+// Station command sent over the websocket, acting as the user of the connection
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action")]
+pub enum ManageRequest {
+    #[serde(rename = "create")]
+    Create {
+        #[serde(rename = "name")]
+        name: String,
+        #[serde(rename = "codeTtlSecs")]
+        code_ttl_secs: Option<u64>,
+    },
+    #[serde(rename = "rename")]
+    Rename {
+        #[serde(rename = "stationId")]
+        station_id: Option<Uuid>,
+        #[serde(rename = "name")]
+        name: String,
+    },
+    #[serde(rename = "rotate_code")]
+    RotateCode {
+        #[serde(rename = "stationId")]
+        station_id: Option<Uuid>,
+        #[serde(rename = "codeTtlSecs")]
+        code_ttl_secs: Option<u64>,
+    },
+    #[serde(rename = "revoke_code")]
+    RevokeCode {
+        #[serde(rename = "stationId")]
+        station_id: Option<Uuid>,
+    },
+    #[serde(rename = "delete")]
+    Delete {
+        #[serde(rename = "stationId")]
+        station_id: Option<Uuid>,
+    },
+    #[serde(rename = "set_visibility")]
+    SetVisibility {
+        #[serde(rename = "stationId")]
+        station_id: Option<Uuid>,
+        #[serde(rename = "visibility")]
+        visibility: Visibility,
+        #[serde(rename = "password")]
+        password: Option<String>,
+    },
+    #[serde(rename = "set_max_listeners")]
+    SetMaxListeners {
+        #[serde(rename = "stationId")]
+        station_id: Option<Uuid>,
+        #[serde(rename = "maxListeners")]
+        max_listeners: Option<usize>,
+    },
+    #[serde(rename = "invite")]
+    Invite {
+        #[serde(rename = "stationId")]
+        station_id: Option<Uuid>,
+        #[serde(rename = "userId")]
+        user_id: usize,
+    },
+    #[serde(rename = "uninvite")]
+    Uninvite {
+        #[serde(rename = "stationId")]
+        station_id: Option<Uuid>,
+        #[serde(rename = "userId")]
+        user_id: usize,
+    },
+}
+
+impl ManageRequest {
+    fn station_id(&self) -> Option<Uuid> {
+        match self {
+            ManageRequest::Create { .. } => None,
+            ManageRequest::Rename { station_id, .. } => *station_id,
+            ManageRequest::RotateCode { station_id, .. } => *station_id,
+            ManageRequest::RevokeCode { station_id } => *station_id,
+            ManageRequest::Delete { station_id } => *station_id,
+            ManageRequest::SetVisibility { station_id, .. } => *station_id,
+            ManageRequest::SetMaxListeners { station_id, .. } => *station_id,
+            ManageRequest::Invite { station_id, .. } => *station_id,
+            ManageRequest::Uninvite { station_id, .. } => *station_id,
+        }
+    }
+}
+
+// Details of a station sent back after it was changed
+#[derive(Serialize, Debug)]
+struct StationInfo<'a> {
+    #[serde(rename = "stationId")]
+    station_id: Uuid,
+    #[serde(rename = "name")]
+    name: &'a str,
+    #[serde(rename = "ownerUsername")]
+    owner_username: &'a str,
+    #[serde(rename = "visibility")]
+    visibility: Visibility,
+    #[serde(rename = "maxListeners", skip_serializing_if = "Option::is_none")]
+    max_listeners: Option<usize>,
+    #[serde(rename = "joinCode", skip_serializing_if = "Option::is_none")]
+    join_code: Option<&'a JoinCode>,
+}
+
+impl<'a> From<&'a Station> for StationInfo<'a> {
+    fn from(station: &'a Station) -> Self {
+        StationInfo {
+            station_id: station.id(),
+            name: station.name(),
+            owner_username: station.owner_username(),
+            visibility: station.visibility(),
+            max_listeners: station.max_listeners(),
+            join_code: station.join_code(),
+        }
+    }
+}
+
+// Construct the redis key mapping a join code to its station
+fn join_code_key(code: &str) -> String {
+    "join-code:".to_owned() + code
+}
+
+// Pick a random join code, uuid v4 bytes are used as the source of randomness
+fn generate_code() -> String {
+    Uuid::new_v4().as_bytes().iter()
+        .take(JOIN_CODE_LEN)
+        .map(|b| JOIN_CODE_ALPHABET[*b as usize % JOIN_CODE_ALPHABET.len()] as char)
+        .collect()
+}
+
+// Trim a station name and ensure it is not empty or too long
+pub fn valid_name(name: &str) -> Result<&str, StationError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(StationError::InvalidName(MAX_NAME_LEN));
+    }
+
+    Ok(name)
+}
+
+// Reserve an unused join code for a station, it stops working after the given amount of seconds
+async fn issue_code(con: &mut Connection, station_id: Uuid, ttl_secs: u64) -> Result<JoinCode, StationError> {
+    let ttl_secs = ttl_secs.max(MIN_JOIN_CODE_TTL_SECS);
+
+    for _ in 0..JOIN_CODE_ATTEMPTS {
+        let code = generate_code();
+        let key = join_code_key(&code);
+
+        // NX leaves codes which are already in use alone
+        let created: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(station_id.to_string())
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl_secs)
+            .query_async(con)
+            .await
+            .map_err(|e| StationError::Redis(key, RedisCMDError(e).into()))?;
+
+        if created.is_some() {
+            return Ok(JoinCode {
+                code,
+                expires_at: unix_millis().saturating_add(ttl_secs.saturating_mul(1000)),
+            });
+        }
+    }
+
+    Err(StationError::NoJoinCode)
+}
+
+// Stop a join code from working, unless it was reused for another station after expiring
+async fn drop_code(con: &mut Connection, station_id: Uuid, join_code: &JoinCode) -> Result<(), StationError> {
+    let key = join_code_key(&join_code.code);
+    let points_to: Option<String> = redis::cmd("GET")
+        .arg(&key)
+        .query_async(con)
+        .await
+        .map_err(|e| StationError::Redis(key.clone(), RedisCMDError(e).into()))?;
+
+    if points_to == Some(station_id.to_string()) {
+        redis::cmd("DEL")
+            .arg(&key)
+            .query_async::<_, i64>(con)
+            .await
+            .map_err(|e| StationError::Redis(key, RedisCMDError(e).into()))?;
+    }
+
+    Ok(())
+}
+
+// Apply a change to a station and save it, returning what the change returned
+// The station is loaded again for every attempt, so changes made in the meantime such as playback moving on are kept
+// The given station is replaced with the one written
+async fn save<T>(con: &mut Connection, station: &mut Station, change: impl FnMut(&mut Station) -> Result<T, StationError>) -> Result<T, StationError> {
+    let (written, changed) = modify(station.id(), con, change).await?;
+    *station = written;
+
+    Ok(changed)
+}
+
+// Create an empty station with a fresh join code
+pub async fn create(con: &mut Connection, owner_username: &str, owner_user_id: usize, name: &str, ttl_secs: u64) -> Result<Station, StationError> {
+    let mut station = Station::new(owner_username, owner_user_id, valid_name(name)?);
+
+    let join_code = issue_code(con, station.id(), ttl_secs).await?;
+    station.set_join_code(Some(join_code));
+
+    // Nobody else knows about the station yet, so it can be written as it is
+    if !to_redis(&station, con).await {
+        return Err(StationError::NotSaved(station.id().to_string()));
+    }
+
+    Ok(station)
+}
+
+// Give a station a new name
+pub async fn rename(con: &mut Connection, station: &mut Station, name: &str) -> Result<(), StationError> {
+    let name = valid_name(name)?;
+
+    save(con, station, |station| {
+        station.rename(name);
+        Ok(())
+    }).await
+}
+
+// Replace the join code of a station, the old code stops working at once
+pub async fn rotate_code(con: &mut Connection, station: &mut Station, ttl_secs: u64) -> Result<(), StationError> {
+    let join_code = issue_code(con, station.id(), ttl_secs).await?;
+
+    let previous = save(con, station, |station| Ok(station.set_join_code(Some(join_code.clone())))).await?;
+    if let Some(previous) = previous {
+        drop_code(con, station.id(), &previous).await?;
+    }
+
+    Ok(())
+}
+
+// Remove the join code of a station so nobody new can join until a code is issued again
+pub async fn revoke_code(con: &mut Connection, station: &mut Station) -> Result<(), StationError> {
+    let previous = save(con, station, |station| Ok(station.set_join_code(None))).await?;
+    if let Some(previous) = previous {
+        drop_code(con, station.id(), &previous).await?;
+    }
+
+    Ok(())
+}
+
+// Change who may join a station, keeping the list of public stations up to date
+pub async fn set_visibility(con: &mut Connection, station: &mut Station, visibility: Visibility, password: Option<&str>) -> Result<(), StationError> {
+    let password_hash = match (visibility, password) {
+        (Visibility::Password, Some(password)) => {
+            if password.is_empty() || password.chars().count() > MAX_PASSWORD_LEN {
+                return Err(StationError::InvalidPassword(MAX_PASSWORD_LEN));
+            }
+
+            Some(hash_password(password).await.ok_or(StationError::NotHashed(station.id().to_string()))?)
+        }
+        _ => None,
+    };
+
+    save(con, station, |station| {
+        let password_hash = match (visibility, &password_hash) {
+            (Visibility::Password, Some(v)) => Some(v.clone()),
+            // Keep the current password when none is given
+            (Visibility::Password, None) => match station.password_hash() {
+                Some(v) => Some(v.to_string()),
+                None => return Err(StationError::InvalidPassword(MAX_PASSWORD_LEN)),
+            },
+            _ => None,
+        };
+        station.set_visibility(visibility, password_hash);
+
+        Ok(())
+    }).await?;
+
+    let command = if visibility == Visibility::Public { "SADD" } else { "SREM" };
+    redis::cmd(command)
+        .arg(PUBLIC_STATIONS_KEY)
+        .arg(station.id().to_string())
+        .query_async::<_, i64>(con)
+        .await
+        .map_err(|e| StationError::Redis(PUBLIC_STATIONS_KEY.to_string(), RedisCMDError(e).into()))?;
+
+    Ok(())
+}
+
+// Change how many listeners a station allows, waiting clients are let in if it grew
+pub async fn set_max_listeners(con: &mut Connection, station: &mut Station, max_listeners: Option<usize>) -> Result<(), StationError> {
+    save(con, station, |station| {
+        station.set_max_listeners(max_listeners);
+        Ok(())
+    }).await?;
+
+    if let Err(e) = fanout::waiting_room_changed(con, station.id()).await {
+        eprintln!("could not update waiting room: {}", e);
+    }
+
+    Ok(())
+}
+
+// Allow a user into an invite only station, or take the invite back
+pub async fn set_invited(con: &mut Connection, station: &mut Station, user_id: usize, invited: bool) -> Result<(), StationError> {
+    save(con, station, |station| {
+        if invited {
+            station.invite(user_id);
+        } else {
+            station.uninvite(user_id);
+        }
+
+        Ok(())
+    }).await
+}
+
+// Get every public station, skipping any which were deleted or made private by hand
+pub async fn public_stations(con: &mut Connection) -> Result<Vec<Station>, StationError> {
+    let ids: Vec<String> = redis::cmd("SMEMBERS")
+        .arg(PUBLIC_STATIONS_KEY)
+        .query_async(con)
+        .await
+        .map_err(|e| StationError::Redis(PUBLIC_STATIONS_KEY.to_string(), RedisCMDError(e).into()))?;
+
+    let mut stations = Vec::new();
+    for station_id in ids.iter().filter_map(|v| Uuid::parse_str(v).ok()) {
+        if let Ok(station) = from_redis(station_id, con).await {
+            if station.visibility() == Visibility::Public {
+                stations.push(station);
+            }
+        }
+    }
+
+    Ok(stations)
+}
+
+// Delete a station and everything kept for it, every instance removes its members once notified
+pub async fn delete(con: &mut Connection, station: &Station) -> Result<(), StationError> {
+    if let Some(join_code) = station.join_code() {
+        drop_code(con, station.id(), join_code).await?;
+    }
+
+    let station_key = "Station_".to_owned() + &station.id().to_string();
+    redis::cmd("DEL")
+        .arg(&station_key)
+        .query_async::<_, i64>(con)
+        .await
+        .map_err(|e| StationError::Redis(station_key, RedisCMDError(e).into()))?;
+
+    let removed = redis::cmd("SREM")
+        .arg(PUBLIC_STATIONS_KEY)
+        .arg(station.id().to_string())
+        .query_async::<_, i64>(con)
+        .await;
+    if let Err(e) = removed {
+        eprintln!("could not remove station from public stations: {}", e);
+    }
+
+    played::forget(con, station.id()).await;
+    votes::forget(con, station.id()).await;
+    // Instances with members clear these too, but a station nobody was in would keep them
+    if let Err(e) = presence::clear(con, station.id()).await {
+        eprintln!("could not clear presence: {}", e);
+    }
+    if let Err(e) = waiting::clear(con, station.id()).await {
+        eprintln!("could not clear waiting room: {}", e);
+    }
+    changes::notify(con, station.id()).await;
+
+    Ok(())
+}
+
+// Status code, error code and message describing a failed station command
+// The message is sent to clients, so it never carries the details of redis or decoding errors
+fn describe(error: &StationError) -> (StatusCode, &'static str, String) {
+    let (status, code, message) = match error {
+        StationError::NotFound(_) => (StatusCode::NOT_FOUND, "not_found", "the station does not exist"),
+        StationError::NotOwner(_) => (StatusCode::FORBIDDEN, "forbidden", "only the station owner can do that"),
+        // Validation errors only describe what the client sent
+        StationError::InvalidName(_) => return (StatusCode::BAD_REQUEST, "invalid_name", error.to_string()),
+        StationError::InvalidPassword(_) => return (StatusCode::BAD_REQUEST, "invalid_password", error.to_string()),
+        StationError::NoJoinCode => (StatusCode::SERVICE_UNAVAILABLE, "no_join_code", "could not find an unused join code"),
+        StationError::Redis(..) | StationError::NotSaved(_) => (StatusCode::SERVICE_UNAVAILABLE, "unavailable", "the station could not be loaded or saved"),
+        StationError::Decode(..) => (StatusCode::INTERNAL_SERVER_ERROR, "invalid_station", "the station could not be read"),
+        StationError::NotHashed(_) => (StatusCode::INTERNAL_SERVER_ERROR, "internal", "the password could not be set"),
+    };
+
+    (status, code, message.to_string())
+}
+
+// Load a station the user owns
+async fn owned(con: &mut Connection, station_id: Uuid, username: &str) -> Result<Station, StationError> {
+    let station = from_redis(station_id, con).await?;
+    if station.owner_username() != username {
+        return Err(StationError::NotOwner(station_id.to_string()));
+    }
+
+    Ok(station)
+}
+
+// Turn the result of a station command into a REST reply
+fn reply(result: Result<Option<&Station>, StationError>, created: bool) -> warp::reply::Response {
+    match result {
+        Ok(Some(station)) => {
+            let status = if created { StatusCode::CREATED } else { StatusCode::OK };
+            with_status(json(&StationInfo::from(station)), status).into_response()
+        }
+        Ok(None) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            eprintln!("station command failed: {}", e);
+            describe(&e).0.into_response()
+        }
+    }
+}
+
+pub async fn create_handler(identity: Identity, body: CreateStationRequest, redis_client: redis::Client, default_ttl_secs: u64) -> crate::Result<impl Reply> {
+    let mut redis_con = match get_con(redis_client).await {
+        Ok(v) => v,
+        Err(_) => return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response()),
+    };
+
+    let ttl_secs = body.code_ttl_secs.unwrap_or(default_ttl_secs);
+    match create(&mut redis_con, &identity.username, identity.user_id, &body.name, ttl_secs).await {
+        Ok(station) => Ok(reply(Ok(Some(&station)), true)),
+        Err(e) => Ok(reply(Err(e), true)),
+    }
+}
+
+pub async fn rename_handler(station_id: Uuid, body: RenameStationRequest, identity: Identity, redis_client: redis::Client) -> crate::Result<impl Reply> {
+    let mut redis_con = match get_con(redis_client).await {
+        Ok(v) => v,
+        Err(_) => return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response()),
+    };
+
+    let mut station = match owned(&mut redis_con, station_id, &identity.username).await {
+        Ok(v) => v,
+        Err(e) => return Ok(reply(Err(e), false)),
+    };
+    let result = rename(&mut redis_con, &mut station, &body.name).await;
+
+    Ok(reply(result.map(|_| Some(&station)), false))
+}
+
+pub async fn delete_handler(station_id: Uuid, identity: Identity, redis_client: redis::Client) -> crate::Result<impl Reply> {
+    let mut redis_con = match get_con(redis_client).await {
+        Ok(v) => v,
+        Err(_) => return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response()),
+    };
+
+    let result = match owned(&mut redis_con, station_id, &identity.username).await {
+        Ok(station) => delete(&mut redis_con, &station).await,
+        Err(e) => Err(e),
+    };
+
+    Ok(reply(result.map(|_| None), false))
+}
+
+pub async fn rotate_code_handler(station_id: Uuid, body: RotateCodeRequest, identity: Identity, redis_client: redis::Client, default_ttl_secs: u64) -> crate::Result<impl Reply> {
+    let mut redis_con = match get_con(redis_client).await {
+        Ok(v) => v,
+        Err(_) => return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response()),
+    };
+
+    let mut station = match owned(&mut redis_con, station_id, &identity.username).await {
+        Ok(v) => v,
+        Err(e) => return Ok(reply(Err(e), false)),
+    };
+    let ttl_secs = body.code_ttl_secs.unwrap_or(default_ttl_secs);
+    let result = rotate_code(&mut redis_con, &mut station, ttl_secs).await;
+
+    Ok(reply(result.map(|_| Some(&station)), false))
+}
+
+pub async fn revoke_code_handler(station_id: Uuid, identity: Identity, redis_client: redis::Client) -> crate::Result<impl Reply> {
+    let mut redis_con = match get_con(redis_client).await {
+        Ok(v) => v,
+        Err(_) => return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response()),
+    };
+
+    let mut station = match owned(&mut redis_con, station_id, &identity.username).await {
+        Ok(v) => v,
+        Err(e) => return Ok(reply(Err(e), false)),
+    };
+    let result = revoke_code(&mut redis_con, &mut station).await;
+
+    Ok(reply(result.map(|_| Some(&station)), false))
+}
+
+pub async fn visibility_handler(station_id: Uuid, body: VisibilityRequest, identity: Identity, redis_client: redis::Client) -> crate::Result<impl Reply> {
+    let mut redis_con = match get_con(redis_client).await {
+        Ok(v) => v,
+        Err(_) => return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response()),
+    };
+
+    let mut station = match owned(&mut redis_con, station_id, &identity.username).await {
+        Ok(v) => v,
+        Err(e) => return Ok(reply(Err(e), false)),
+    };
+    let result = set_visibility(&mut redis_con, &mut station, body.visibility, body.password.as_deref()).await;
+
+    Ok(reply(result.map(|_| Some(&station)), false))
+}
+
+pub async fn capacity_handler(station_id: Uuid, body: CapacityRequest, identity: Identity, redis_client: redis::Client) -> crate::Result<impl Reply> {
+    let mut redis_con = match get_con(redis_client).await {
+        Ok(v) => v,
+        Err(_) => return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response()),
+    };
+
+    let mut station = match owned(&mut redis_con, station_id, &identity.username).await {
+        Ok(v) => v,
+        Err(e) => return Ok(reply(Err(e), false)),
+    };
+    let result = set_max_listeners(&mut redis_con, &mut station, body.max_listeners).await;
+
+    Ok(reply(result.map(|_| Some(&station)), false))
+}
+
+pub async fn invite_handler(station_id: Uuid, user_id: usize, invited: bool, identity: Identity, redis_client: redis::Client) -> crate::Result<impl Reply> {
+    let mut redis_con = match get_con(redis_client).await {
+        Ok(v) => v,
+        Err(_) => return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response()),
+    };
+
+    let mut station = match owned(&mut redis_con, station_id, &identity.username).await {
+        Ok(v) => v,
+        Err(e) => return Ok(reply(Err(e), false)),
+    };
+    let result = set_invited(&mut redis_con, &mut station, user_id, invited).await;
+
+    Ok(reply(result.map(|_| None), false))
+}
+
+pub async fn list_handler(_identity: Identity, redis_client: redis::Client) -> crate::Result<impl Reply> {
+    let mut redis_con = match get_con(redis_client).await {
+        Ok(v) => v,
+        Err(_) => return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response()),
+    };
+
+    match public_stations(&mut redis_con).await {
+        Ok(stations) => {
+            let infos: Vec<StationInfo> = stations.iter().map(StationInfo::from).collect();
+            Ok(json(&infos).into_response())
+        }
+        Err(e) => Ok(reply(Err(e), false)),
+    }
+}
+
+// Receiver for users creating stations and owners managing them
+pub struct ManageReceiver {
+    pub stations: Arc<StationManager>,
+    // Seconds a join code works for when the request does not say
+    pub join_code_ttl_secs: u64,
+}
+
+impl ManageReceiver {
+    // Apply a command to an existing station owned by the user
+    async fn manage(&self, con: &mut Connection, username: &str, station_id: Uuid, request: ManageRequest) -> Result<Option<Station>, StationError> {
+        let mut station = owned(con, station_id, username).await?;
+
+        match request {
+            ManageRequest::Create { .. } => return Ok(None),
+            ManageRequest::Rename { name, .. } => rename(con, &mut station, &name).await?,
+            ManageRequest::RotateCode { code_ttl_secs, .. } => {
+                rotate_code(con, &mut station, code_ttl_secs.unwrap_or(self.join_code_ttl_secs)).await?
+            }
+            ManageRequest::RevokeCode { .. } => revoke_code(con, &mut station).await?,
+            ManageRequest::Delete { .. } => {
+                delete(con, &station).await?;
+                return Ok(None);
+            }
+            ManageRequest::SetVisibility { visibility, password, .. } => {
+                set_visibility(con, &mut station, visibility, password.as_deref()).await?
+            }
+            ManageRequest::SetMaxListeners { max_listeners, .. } => {
+                set_max_listeners(con, &mut station, max_listeners).await?
+            }
+            ManageRequest::Invite { user_id, .. } => set_invited(con, &mut station, user_id, true).await?,
+            ManageRequest::Uninvite { user_id, .. } => set_invited(con, &mut station, user_id, false).await?,
+        }
+
+        Ok(Some(station))
+    }
+}
+
+#[async_trait]
+impl Receiver for ManageReceiver {
+    async fn receive_msg(&self, id: &str, msg: &str, clients: &Clients, redis_client: redis::Client) {
+        let request: ManageRequest = match from_str(msg) {
+            Ok(v) => v,
+            Err(_) => {
+                ws::send_error(clients, id, "invalid_request", "expected a station command").await;
+                return;
+            }
+        };
+
+        // Stations belong to the account the connection registered with
+        let (username, user_id) = match clients.read().await.get(id) {
+            Some(c) => (c.username.clone(), c.user_id),
+            None => return,
+        };
+
+        // Establish connection to redis
+        let mut redis_con = match get_con(redis_client).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis");
+                return;
+            }
+        };
+
+        let result = match request {
+            ManageRequest::Create { name, code_ttl_secs } => {
+                let ttl_secs = code_ttl_secs.unwrap_or(self.join_code_ttl_secs);
+                create(&mut redis_con, &username, user_id, &name, ttl_secs).await.map(Some)
+            }
+            request => {
+                // The station may be left out when the client is only in one station
+                let station_id = match request.station_id() {
+                    Some(v) => Some(v),
+                    None => self.stations.resolve_station(id, None).await,
+                };
+                match station_id {
+                    Some(station_id) => {
+                        let deleting = matches!(request, ManageRequest::Delete { .. });
+                        let result = self.manage(&mut redis_con, &username, station_id, request).await;
+
+                        // Members hear about the deletion from the station itself, tell the owner if they are not one
+                        if deleting && result.is_ok() && !self.stations.joined_stations(id).await.contains(&station_id) {
+                            ws::send_json(clients, id, "station_deleted", &StationRef { station_id }).await;
+                        }
+
+                        result
+                    }
+                    None => {
+                        ws::send_error(clients, id, "not_in_station", "say which station to manage").await;
+                        return;
+                    }
+                }
+            }
+        };
+
+        match result {
+            Ok(Some(station)) => ws::send_json(clients, id, "station", &StationInfo::from(&station)).await,
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("station command failed: {}", e);
+                let (_, code, message) = describe(&e);
+                ws::send_error(clients, id, code, &message).await;
+            }
+        }
+    }
+}
@@ -8,9 +8,26 @@ use crate::Clients;
 pub trait Receiver: Send + Sync {
     // Function is implemented by receivers and ran when a message is received
     async fn receive_msg(&self, id: &str, msg: &str, clients: &Clients, redis_client: redis::Client);
+
+    // Function is ran instead of receive_msg with the unix time in milliseconds the frame arrived at
+    // Only receivers which report the time, such as time sync, need to implement it
+    async fn receive_timed(&self, id: &str, msg: &str, _received_at: u64, clients: &Clients, redis_client: redis::Client) {
+        self.receive_msg(id, msg, clients, redis_client).await
+    }
+
+    // Function is ran before a client is removed after disconnecting
+    async fn client_disconnected(&self, _id: &str, _clients: &Clients, _redis_client: redis::Client) {}
 }
 
 // Structure for storing a list of receivers
 pub struct ReceiverManager {
     pub receivers: HashMap<String, Arc<dyn Receiver>>,
-}
\ No newline at end of file
+}
+impl ReceiverManager {
+    // Let every receiver clean up after a disconnected client
+    pub async fn client_disconnected(&self, id: &str, clients: &Clients, redis_client: redis::Client) {
+        for receiver in self.receivers.values() {
+            receiver.client_disconnected(id, clients, redis_client.clone()).await;
+        }
+    }
+}
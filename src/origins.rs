@@ -0,0 +1,145 @@
+use std::str::FromStr;
+use warp::{Filter, Rejection, Reply};
+use warp::cors::Builder;
+use warp::http::{HeaderValue, StatusCode, Uri};
+
+// Origin allowing every website
+const ANY_ORIGIN: &str = "*";
+
+// Websites allowed to call the REST routes and open websockets, read from a comma separated list
+// Requests without an Origin header come from backends and native clients, they are always allowed
+#[derive(Debug, Clone, Default)]
+pub struct Origins {
+    any: bool,
+    allowed: Vec<String>,
+}
+
+impl FromStr for Origins {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut origins = Origins::default();
+
+        for origin in value.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            if origin == ANY_ORIGIN {
+                origins.any = true;
+                continue;
+            }
+
+            // An origin is a scheme and host with an optional port, nothing else
+            let origin = origin.trim_end_matches('/').to_ascii_lowercase();
+            let uri: Uri = origin.parse().map_err(|_| format!("invalid origin {}", origin))?;
+            let has_path = uri.path_and_query().is_some_and(|p| p.as_str() != "/");
+            if uri.scheme().is_none() || uri.host().is_none() || has_path || HeaderValue::from_str(&origin).is_err() {
+                return Err(format!("expected <scheme>://<host>[:<port>] instead of {}", origin));
+            }
+
+            origins.allowed.push(origin);
+        }
+
+        Ok(origins)
+    }
+}
+
+impl Origins {
+    // Check if a website may make requests
+    pub fn allows(&self, origin: &str) -> bool {
+        self.any || self.allowed.iter().any(|o| o.eq_ignore_ascii_case(origin))
+    }
+
+    // CORS settings for the REST routes
+    pub fn cors(&self) -> Builder {
+        let cors = warp::cors()
+            .allow_headers(vec!["content-type", "authorization", "x-vradio-key-id", "x-vradio-timestamp", "x-vradio-signature", "x-vradio-password"])
+            .allow_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"]);
+
+        if self.any {
+            cors.allow_any_origin()
+        } else {
+            cors.allow_origins(self.allowed.iter().map(String::as_str))
+        }
+    }
+}
+
+// Rejection for requests from websites which are not allowed
+#[derive(Debug)]
+struct ForbiddenOrigin;
+impl warp::reject::Reject for ForbiddenOrigin {}
+
+// Filter rejecting requests from websites which are not allowed
+// Browsers do not apply CORS to websocket upgrades, so the server has to check the origin itself
+pub fn check(origins: Origins) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("origin")
+        .and_then(move |origin: Option<String>| {
+            let allowed = origin.as_deref().is_none_or(|o| origins.allows(o));
+            async move {
+                if allowed {
+                    Ok(())
+                } else {
+                    eprintln!("rejected websocket from origin {}", origin.unwrap_or_default());
+                    Err(warp::reject::custom(ForbiddenOrigin))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// Answer requests from websites which are not allowed with 403, leaving other rejections to warp
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<ForbiddenOrigin>().is_some() {
+        return Ok(warp::reply::with_status("origin not allowed", StatusCode::FORBIDDEN));
+    }
+
+    Err(rejection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_str() {
+        let cases = [
+            // (list, accepted)
+            ("", true),
+            ("*", true),
+            ("https://example.com", true),
+            ("https://example.com/", true),
+            (" https://example.com , http://localhost:3000 ,", true),
+            ("https://example.com,*", true),
+            ("example.com", false),
+            ("https://", false),
+            ("https://example.com/app", false),
+            ("https://example.com?page=1", false),
+            ("https://example.com,not an origin", false),
+        ];
+
+        for (list, accepted) in cases {
+            assert_eq!(list.parse::<Origins>().is_ok(), accepted, "{}", list);
+        }
+    }
+
+    #[test]
+    fn allows() {
+        let origins: Origins = "https://Example.com/, http://localhost:3000".parse().unwrap();
+        let cases = [
+            // (origin, allowed)
+            ("https://example.com", true),
+            ("HTTPS://EXAMPLE.COM", true),
+            ("http://localhost:3000", true),
+            ("http://example.com", false),
+            ("https://example.com:8443", false),
+            ("http://localhost", false),
+            ("https://example.com.evil.com", false),
+            ("", false),
+        ];
+
+        for (origin, allowed) in cases {
+            assert_eq!(origins.allows(origin), allowed, "{}", origin);
+        }
+
+        // Nothing is allowed until it is listed, and * allows everything
+        assert!(!Origins::default().allows("https://example.com"));
+        assert!("*".parse::<Origins>().unwrap().allows("https://example.com"));
+    }
+}
@@ -0,0 +1,260 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use redis::aio::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::from_str;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::Reply;
+use warp::reply::{json, with_status};
+use crate::{access, Clients, StationError, ws};
+use crate::DirectError::RedisCMDError;
+use crate::identity::Identity;
+use crate::message_receive::Receiver;
+use crate::redis_direct::{get_con, Result};
+use crate::station::{from_redis, modify, Media, StationManager};
+
+// Maximum amount of finished media kept for each station
+const MAX_PLAYED: usize = 100;
+// Amount of entries returned when no limit is given
+const DEFAULT_LIMIT: usize = 20;
+
+// Media which finished playing in a station
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlayedEntry {
+    #[serde(rename = "media")]
+    pub media: Media,
+    // Unix time in milliseconds the media started playing, unknown if no instance was timing it
+    #[serde(rename = "startedAt", default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<u64>,
+    // Unix time in milliseconds the media stopped playing
+    #[serde(rename = "endedAt")]
+    pub ended_at: u64,
+    // True if the media was skipped before it finished
+    #[serde(rename = "skipped")]
+    pub skipped: bool,
+}
+
+// Request for the recently played media of a station
+#[derive(Deserialize, Debug)]
+pub struct HistoryRequest {
+    #[serde(rename = "stationId")]
+    station_id: Option<Uuid>,
+    #[serde(rename = "limit")]
+    limit: Option<usize>,
+}
+
+// Query string of the history route
+#[derive(Deserialize, Debug)]
+pub struct HistoryQuery {
+    limit: Option<usize>,
+}
+
+// Recently played media of a station, newest first
+#[derive(Serialize, Debug)]
+struct HistoryResponse {
+    #[serde(rename = "stationId")]
+    station_id: Uuid,
+    #[serde(rename = "entries")]
+    entries: Vec<PlayedEntry>,
+}
+
+// Request from the owner or a DJ to queue a recently played media again
+#[derive(Deserialize, Debug)]
+pub struct PlayAgainRequest {
+    #[serde(rename = "stationId")]
+    station_id: Option<Uuid>,
+    // Position in the history, 0 is the most recent
+    #[serde(rename = "index")]
+    index: usize,
+}
+
+// Construct the redis key of the played list of a station
+fn played_key(station_id: Uuid) -> String {
+    "station-history:".to_owned() + &station_id.to_string()
+}
+
+// Add finished media to the front of the played list of a station
+pub async fn record(con: &mut Connection, station_id: Uuid, entry: &PlayedEntry) {
+    let as_json = match serde_json::to_string(entry) {
+        Ok(v) => v,
+        Err(_) => {
+            eprintln!("Could not serialize played media");
+            return;
+        }
+    };
+
+    let result = redis::pipe()
+        .cmd("LPUSH").arg(played_key(station_id)).arg(as_json).ignore()
+        .cmd("LTRIM").arg(played_key(station_id)).arg(0).arg(MAX_PLAYED - 1).ignore()
+        .query_async::<_, ()>(con)
+        .await;
+
+    if let Err(e) = result {
+        eprintln!("could not record played media: {}", e);
+    }
+}
+
+// Drop the played list of a deleted station
+pub async fn forget(con: &mut Connection, station_id: Uuid) {
+    let result = redis::cmd("DEL")
+        .arg(played_key(station_id))
+        .query_async::<_, i64>(con)
+        .await;
+
+    if let Err(e) = result {
+        eprintln!("could not forget played media: {}", e);
+    }
+}
+
+// Get the most recently played media of a station, newest first
+pub async fn recent(con: &mut Connection, station_id: Uuid, limit: usize) -> Result<Vec<PlayedEntry>> {
+    let limit = limit.clamp(1, MAX_PLAYED);
+    let entries: Vec<String> = redis::cmd("LRANGE")
+        .arg(played_key(station_id))
+        .arg(0)
+        .arg(limit - 1)
+        .query_async(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    // Skip entries which could not be read
+    Ok(entries.iter().filter_map(|v| serde_json::from_str(v).ok()).collect())
+}
+
+pub async fn history_handler(station_id: Uuid, identity: Identity, password: Option<String>, query: HistoryQuery, redis_client: redis::Client) -> crate::Result<impl Reply> {
+    let mut redis_con = match get_con(redis_client).await {
+        Ok(v) => v,
+        Err(_) => return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response()),
+    };
+
+    // Only users who could join the station may see what it played
+    let station = match from_redis(station_id, &mut redis_con).await {
+        Ok(v) => v,
+        Err(StationError::NotFound(_)) => return Ok(StatusCode::NOT_FOUND.into_response()),
+        Err(e) => {
+            eprintln!("{}", e);
+            return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response());
+        }
+    };
+    if let Err(denied) = access::admit(&mut redis_con, &station, &identity.username, identity.user_id, password.as_deref()).await {
+        return Ok(with_status(denied.code(), StatusCode::FORBIDDEN).into_response());
+    }
+
+    // Return the recently played media of the station
+    match recent(&mut redis_con, station_id, query.limit.unwrap_or(DEFAULT_LIMIT)).await {
+        Ok(entries) => Ok(json(&HistoryResponse { station_id, entries }).into_response()),
+        Err(e) => {
+            eprintln!("could not load played media: {}", e);
+            Ok(StatusCode::SERVICE_UNAVAILABLE.into_response())
+        }
+    }
+}
+
+// Receiver for querying the recently played media of a station
+pub struct HistoryReceiver {
+    pub stations: Arc<StationManager>,
+}
+#[async_trait]
+impl Receiver for HistoryReceiver {
+    async fn receive_msg(&self, id: &str, msg: &str, clients: &Clients, redis_client: redis::Client) {
+        let request: HistoryRequest = match from_str(msg) {
+            Ok(v) => v,
+            Err(_) => {
+                ws::send_error(clients, id, "invalid_request", "expected a history request").await;
+                return;
+            }
+        };
+
+        // Ensure the client is in the station
+        let station_id = match self.stations.resolve_station(id, request.station_id).await {
+            Some(v) => v,
+            None => {
+                ws::send_error(clients, id, "not_in_station", "join the station to see its history").await;
+                return;
+            }
+        };
+
+        // Establish connection to redis
+        let mut redis_con = match get_con(redis_client).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis");
+                return;
+            }
+        };
+
+        match recent(&mut redis_con, station_id, request.limit.unwrap_or(DEFAULT_LIMIT)).await {
+            Ok(entries) => ws::send_json(clients, id, "history", &HistoryResponse { station_id, entries }).await,
+            Err(e) => eprintln!("could not load played media: {}", e),
+        }
+    }
+}
+
+// Receiver for the owner or a DJ queueing a recently played media again
+pub struct PlayAgainReceiver {
+    pub stations: Arc<StationManager>,
+}
+#[async_trait]
+impl Receiver for PlayAgainReceiver {
+    async fn receive_msg(&self, id: &str, msg: &str, clients: &Clients, redis_client: redis::Client) {
+        let request: PlayAgainRequest = match from_str(msg) {
+            Ok(v) => v,
+            Err(_) => {
+                ws::send_error(clients, id, "invalid_request", "expected a play again request").await;
+                return;
+            }
+        };
+
+        // Ensure the client is in the station
+        let station_id = match self.stations.resolve_station(id, request.station_id).await {
+            Some(v) => v,
+            None => {
+                ws::send_error(clients, id, "not_in_station", "join the station before queueing").await;
+                return;
+            }
+        };
+
+        // Establish connection to redis
+        let mut redis_con = match get_con(redis_client).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis");
+                return;
+            }
+        };
+
+        // Only the owner and DJs of the station may queue media again
+        let station = match from_redis(station_id, &mut redis_con).await {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+        let can_manage = clients.read().await.get(id).is_some_and(|c| station.role_of(c).can_manage());
+        if !can_manage {
+            ws::send_error(clients, id, "forbidden", "only the station owner and DJs can queue media again").await;
+            return;
+        }
+
+        // Find the media in the history
+        let entry = match recent(&mut redis_con, station_id, request.index + 1).await {
+            Ok(mut v) if v.len() > request.index => v.swap_remove(request.index),
+            _ => {
+                ws::send_error(clients, id, "not_found", "no played media at that position").await;
+                return;
+            }
+        };
+
+        // Add it to the end of the latest queue, playback starts if nothing was playing
+        let saved = modify(station_id, &mut redis_con, |station| {
+            station.enqueue(entry.media.clone());
+            Ok::<_, StationError>(())
+        }).await;
+        if let Err(e) = saved {
+            eprintln!("could not queue media again: {}", e);
+            ws::send_error(clients, id, "unavailable", "could not save the station").await;
+        }
+    }
+}
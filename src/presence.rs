@@ -0,0 +1,188 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use async_trait::async_trait;
+use redis::aio::Connection;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::ws::Message;
+use crate::{Clients, fanout, instance};
+use crate::DirectError::RedisCMDError;
+use crate::message_receive::Receiver;
+use crate::redis_direct::{get_con, Result};
+use crate::station::StationManager;
+
+// A user tuned in to a station
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Listener {
+    #[serde(rename = "userId")]
+    pub user_id: usize,
+    #[serde(rename = "displayName")]
+    pub display_name: String,
+}
+
+// Event sent to listeners when someone joins or leaves their station
+#[derive(Debug, Serialize)]
+struct PresenceEvent {
+    #[serde(rename = "stationId")]
+    station_id: Uuid,
+    #[serde(rename = "userId")]
+    user_id: usize,
+    #[serde(rename = "displayName")]
+    display_name: String,
+    #[serde(rename = "count")]
+    count: usize,
+}
+
+// Response to a listeners query
+#[derive(Debug, Serialize)]
+struct Roster {
+    #[serde(rename = "stationId")]
+    station_id: Uuid,
+    #[serde(rename = "count")]
+    count: usize,
+    #[serde(rename = "listeners")]
+    listeners: Vec<Listener>,
+}
+
+// Construct the redis key of the presence hash for a station
+pub fn presence_key(station_id: Uuid) -> String {
+    "station-presence:".to_owned() + &station_id.to_string()
+}
+
+// Construct the presence hash field of a connection, prefixed with the instance holding the connection
+pub fn presence_field(client_id: &str) -> String {
+    format!("{}:{}", instance::id(), client_id)
+}
+
+// Get every user listening to a station across all instances
+// Connections of instances which stopped sending heartbeats are removed on the way
+pub async fn roster(con: &mut Connection, station_id: Uuid) -> Result<Vec<Listener>> {
+    // The hash maps <instance id>:<connection id> to listeners
+    let entries: HashMap<String, String> = redis::cmd("HGETALL")
+        .arg(presence_key(station_id))
+        .query_async(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    // Fields without an instance were written before instances had heartbeats, nobody removes them otherwise
+    let instances: HashSet<&str> = entries.keys().filter_map(|f| f.split_once(':').map(|(i, _)| i)).collect();
+    let alive = instance::alive(con, &instances).await?;
+    let (live, dead): (Vec<_>, Vec<_>) = entries.iter()
+        .partition(|(field, _)| field.split_once(':').is_some_and(|(i, _)| alive.contains(i)));
+
+    if !dead.is_empty() {
+        redis::cmd("HDEL")
+            .arg(presence_key(station_id))
+            .arg(dead.iter().map(|(field, _)| field.as_str()).collect::<Vec<_>>())
+            .query_async::<_, i64>(con)
+            .await
+            .map_err(RedisCMDError)?;
+    }
+
+    // A user with several connections is only listed once
+    let mut listeners: Vec<Listener> = Vec::new();
+    for listener in live.iter().filter_map(|(_, v)| serde_json::from_str::<Listener>(v).ok()) {
+        if !listeners.iter().any(|l| l.user_id == listener.user_id) {
+            listeners.push(listener);
+        }
+    }
+
+    Ok(listeners)
+}
+
+// Record a connection as listening to a station and tell every listener
+pub async fn joined(con: &mut Connection, station_id: Uuid, client_id: &str, listener: &Listener) -> Result<()> {
+    let as_json = serde_json::to_string(listener).unwrap_or_default();
+    redis::cmd("HSET")
+        .arg(presence_key(station_id))
+        .arg(presence_field(client_id))
+        .arg(as_json)
+        .query_async::<_, i64>(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    announce(con, "listener_joined", station_id, listener).await
+}
+
+// Remove a connection from the listeners of a station and tell the remaining listeners
+pub async fn left(con: &mut Connection, station_id: Uuid, client_id: &str, listener: &Listener) -> Result<()> {
+    redis::cmd("HDEL")
+        .arg(presence_key(station_id))
+        .arg(presence_field(client_id))
+        .query_async::<_, i64>(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    announce(con, "listener_left", station_id, listener).await?;
+
+    // The place the connection held may let someone in from the waiting room
+    fanout::waiting_room_changed(con, station_id).await
+}
+
+// Forget every listener of a station
+pub async fn clear(con: &mut Connection, station_id: Uuid) -> Result<()> {
+    redis::cmd("DEL")
+        .arg(presence_key(station_id))
+        .query_async::<_, i64>(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    Ok(())
+}
+
+// Tell the members of a station on every instance about a presence change
+async fn announce(con: &mut Connection, event: &str, station_id: Uuid, listener: &Listener) -> Result<()> {
+    let count = roster(con, station_id).await?.len();
+
+    let presence_event = PresenceEvent {
+        station_id,
+        user_id: listener.user_id,
+        display_name: listener.display_name.clone(),
+        count,
+    };
+
+    fanout::send_json(con, station_id, event, &presence_event).await
+}
+
+// Receiver for querying who is listening to the stations a client joined
+pub struct ListenersReceiver {
+    pub stations: Arc<StationManager>,
+}
+#[async_trait]
+impl Receiver for ListenersReceiver {
+    async fn receive_msg(&self, id: &str, _msg: &str, clients: &Clients, redis_client: redis::Client) {
+        // Establish connection to redis
+        let mut redis_con = match get_con(redis_client).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis");
+                return;
+            }
+        };
+
+        // Build the roster of every station the client is in
+        let mut rosters = HashMap::new();
+        for station_id in self.stations.joined_stations(id).await {
+            match roster(&mut redis_con, station_id).await {
+                Ok(listeners) => {
+                    rosters.insert(station_id, listeners);
+                }
+                Err(e) => eprintln!("could not load listeners of station {}: {}", station_id, e),
+            }
+        }
+
+        if let Some(sender) = clients.read().await.get(id).and_then(|c| c.sender.as_ref()) {
+            for (station_id, listeners) in rosters {
+                let roster = Roster {
+                    station_id,
+                    count: listeners.len(),
+                    listeners,
+                };
+
+                if let Ok(as_json) = serde_json::to_string(&roster) {
+                    let _ = sender.send(Ok(Message::text("listeners=".to_string() + &as_json)));
+                }
+            }
+        }
+    }
+}
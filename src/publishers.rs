@@ -0,0 +1,475 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use warp::{Filter, Rejection, Reply};
+use warp::http::{Method, StatusCode};
+use warp::http::header::AUTHORIZATION;
+use warp::path::FullPath;
+use warp::hyper::body::Bytes;
+use crate::redis_direct::get_con;
+use crate::tls;
+use crate::timer::unix_millis;
+
+// Largest body accepted from a publisher, the signature has to be checked before the body is read
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+// Signed requests older or newer than this are rejected so they can not be replayed later
+const MAX_CLOCK_SKEW_SECS: u64 = 300;
+// Seconds a used signature is remembered, long enough to cover every timestamp which would still be accepted
+const USED_SIGNATURE_SECS: u64 = 2 * MAX_CLOCK_SKEW_SECS;
+// Headers of a signed request
+const KEY_ID_HEADER: &str = "x-vradio-key-id";
+const TIMESTAMP_HEADER: &str = "x-vradio-timestamp";
+const SIGNATURE_HEADER: &str = "x-vradio-signature";
+// Topic scope allowing every topic
+const ANY_TOPIC: &str = "*";
+
+// Key a backend uses to publish, with the topics and users it may publish to
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    #[serde(rename = "id")]
+    pub id: String,
+    #[serde(rename = "secret")]
+    secret: String,
+    // Topics the key may publish to, * allows every topic
+    #[serde(rename = "topics")]
+    topics: Vec<String>,
+    // Users the key may publish to, every user when missing
+    // A key limited to users can not broadcast to a whole topic
+    #[serde(rename = "userIds")]
+    user_ids: Option<Vec<usize>>,
+}
+
+impl ApiKey {
+    // Check if the key may publish to a topic and optionally a single user
+    pub fn allows(&self, topic: &str, user_id: Option<usize>) -> bool {
+        let topic_allowed = self.topics.iter().any(|t| t == ANY_TOPIC || t == topic);
+        let user_allowed = match (&self.user_ids, user_id) {
+            (None, _) => true,
+            (Some(allowed), Some(user_id)) => allowed.contains(&user_id),
+            (Some(_), None) => false,
+        };
+
+        topic_allowed && user_allowed
+    }
+
+    // Key used when authentication is turned off
+    fn unrestricted() -> ApiKey {
+        ApiKey {
+            id: "anonymous".to_string(),
+            secret: String::new(),
+            topics: vec![ANY_TOPIC.to_string()],
+            user_ids: None,
+        }
+    }
+}
+
+// Keys loaded from the config, read as a json array of keys
+// More keys can be added at runtime by storing them as json under publish-key:<id> in redis
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeys {
+    keys: Vec<ApiKey>,
+}
+
+impl FromStr for ApiKeys {
+    type Err = serde_json::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(ApiKeys { keys: serde_json::from_str(value)? })
+    }
+}
+
+// Construct the redis key of a publish key
+fn key_key(id: &str) -> String {
+    "publish-key:".to_owned() + id
+}
+
+// Construct the redis key remembering a signature was used
+fn signature_key(signature: &str) -> String {
+    "publish-signature:".to_owned() + &signature.to_ascii_lowercase()
+}
+
+// What a signature covers besides the timestamp
+pub struct SignedRequest<'a> {
+    method: &'a Method,
+    path: &'a str,
+    body: &'a [u8],
+}
+
+// Settings needed to authenticate publishers
+#[derive(Clone)]
+pub struct Authenticator {
+    // Whether publishers have to authenticate at all
+    pub enabled: bool,
+    pub keys: ApiKeys,
+    pub redis_client: redis::Client,
+}
+
+impl Authenticator {
+    // Find a key in the config, then in redis
+    async fn find(&self, id: &str) -> Option<ApiKey> {
+        if let Some(key) = self.keys.keys.iter().find(|k| k.id == id) {
+            return Some(key.clone());
+        }
+
+        // Establish connection to redis
+        let mut redis_con = match get_con(self.redis_client.clone()).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis");
+                return None;
+            }
+        };
+
+        let value: Option<String> = match redis::cmd("GET").arg(key_key(id)).query_async(&mut redis_con).await {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("could not load publish key {}: {}", id, e);
+                return None;
+            }
+        };
+
+        match serde_json::from_str::<ApiKey>(&value?) {
+            // The id in the redis key is the one which counts
+            Ok(key) => Some(ApiKey { id: id.to_string(), ..key }),
+            Err(e) => {
+                eprintln!("invalid publish key {}: {}", id, e);
+                None
+            }
+        }
+    }
+
+    // Remember a signature, returns false if it was used before
+    // Every instance shares the record so a request can not be replayed against another one
+    async fn first_use(&self, signature: &str) -> Result<bool, &'static str> {
+        let mut redis_con = get_con(self.redis_client.clone()).await.map_err(|_| "could not connect to redis")?;
+
+        let stored: Option<String> = redis::cmd("SET")
+            .arg(signature_key(signature))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(USED_SIGNATURE_SECS)
+            .query_async(&mut redis_con)
+            .await
+            .map_err(|_| "could not record signature")?;
+
+        Ok(stored.is_some())
+    }
+
+    // Check the credentials of a request, returning the key it was made with
+    async fn authenticate(&self, credentials: &Credentials, request: &SignedRequest<'_>) -> Result<ApiKey, &'static str> {
+        if !self.enabled {
+            return Ok(ApiKey::unrestricted());
+        }
+
+        match credentials {
+            Credentials::Missing => Err("missing credentials"),
+            Credentials::Bearer(token) => {
+                let (id, secret) = token.split_once('.').ok_or("malformed api key")?;
+                let key = self.find(id).await.ok_or("unknown key")?;
+                if constant_time_eq(key.secret.as_bytes(), secret.as_bytes()) {
+                    Ok(key)
+                } else {
+                    Err("wrong secret")
+                }
+            }
+            Credentials::Signed { key_id, timestamp, signature } => {
+                check_timestamp(timestamp, unix_millis() / 1000)?;
+                let signature = decode_hex(signature).ok_or("malformed signature")?;
+                let key = self.find(key_id).await.ok_or("unknown key")?;
+                check_signature(&key.secret, timestamp, &signature, request)?;
+
+                // A valid signature only works once
+                if !self.first_use(&hex_signature(&signature)).await? {
+                    return Err("signature already used");
+                }
+
+                Ok(key)
+            }
+        }
+    }
+}
+
+// Credentials sent with a request
+enum Credentials {
+    Missing,
+    // Authorization: Bearer <key id>.<secret>
+    Bearer(String),
+    // Hex encoded HMAC-SHA256 of <timestamp>.<method>.<path>.<body> with the key secret
+    Signed { key_id: String, timestamp: String, signature: String },
+}
+
+impl Credentials {
+    // Id of the key the request claims to use, for logging
+    fn key_id(&self) -> Option<&str> {
+        match self {
+            Credentials::Missing => None,
+            Credentials::Bearer(token) => Some(token.split_once('.').map_or(token.as_str(), |(id, _)| id)),
+            Credentials::Signed { key_id, .. } => Some(key_id),
+        }
+    }
+}
+
+// Read the credentials from the request headers, a signature is preferred over a bearer key
+fn credentials(authorization: Option<String>, key_id: Option<String>, timestamp: Option<String>, signature: Option<String>) -> Credentials {
+    if let (Some(key_id), Some(timestamp), Some(signature)) = (key_id, timestamp, signature) {
+        return Credentials::Signed { key_id, timestamp, signature };
+    }
+
+    match authorization.as_deref().and_then(|a| a.strip_prefix("Bearer ")) {
+        Some(token) => Credentials::Bearer(token.trim().to_string()),
+        None => Credentials::Missing,
+    }
+}
+
+// Ensure a signed request was sent close enough to the server time
+fn check_timestamp(timestamp: &str, now_secs: u64) -> Result<(), &'static str> {
+    let sent_at: u64 = timestamp.parse().map_err(|_| "malformed timestamp")?;
+    if now_secs.abs_diff(sent_at) > MAX_CLOCK_SKEW_SECS {
+        return Err("timestamp too far from server time");
+    }
+
+    Ok(())
+}
+
+// Check the signature of a request made with a key secret
+fn check_signature(secret: &str, timestamp: &str, signature: &[u8], request: &SignedRequest<'_>) -> Result<(), &'static str> {
+    // Sign the timestamp together with the request so none of it can be swapped
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).map_err(|_| "invalid key")?;
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(request.method.as_str().as_bytes());
+    mac.update(b".");
+    mac.update(request.path.as_bytes());
+    mac.update(b".");
+    mac.update(request.body);
+    mac.verify_slice(signature).map_err(|_| "wrong signature")
+}
+
+// Compare secrets without leaking how much of them matched through timing
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Decode a hex string into bytes
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    // from_str_radix would also take a sign in front of a digit
+    if !value.len().is_multiple_of(2) || !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    (0..value.len())
+        .step_by(2)
+        .map(|i| value.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+// Encode a signature as lowercase hex, clients may send it in either case
+fn hex_signature(signature: &[u8]) -> String {
+    signature.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Log a publisher which was turned away
+pub fn log_rejected(key_id: Option<&str>, addr: Option<SocketAddr>, reason: &str) {
+    let addr = addr.map(|a| a.to_string()).unwrap_or_else(|| "unknown".to_string());
+    eprintln!("publish rejected: key={} addr={} reason={}", key_id.unwrap_or("none"), addr, reason);
+}
+
+// Rejection for requests without valid publisher credentials
+#[derive(Debug)]
+struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+// Filter authenticating a publisher, extracting its key and the raw body the signature was checked against
+pub fn authenticate(authenticator: Authenticator) -> impl Filter<Extract = (ApiKey, Bytes), Error = Rejection> + Clone {
+    with_credentials()
+        .and(warp::body::content_length_limit(MAX_BODY_BYTES))
+        .and(warp::body::bytes())
+        .and(warp::any().map(move || authenticator.clone()))
+        .and_then(|credentials, addr, method: Method, path: FullPath, body: Bytes, authenticator: Authenticator| async move {
+            let request = SignedRequest { method: &method, path: path.as_str(), body: &body };
+            verify(&authenticator, &credentials, addr, &request).await.map(|key| (key, body))
+        })
+        .untuple_one()
+}
+
+// Filter authenticating a publisher for requests without a body, extracting its key
+// Signed requests sign an empty body, the method and path still tie the signature to one request
+pub fn authorized(authenticator: Authenticator) -> impl Filter<Extract = (ApiKey,), Error = Rejection> + Clone {
+    with_credentials()
+        .and(warp::any().map(move || authenticator.clone()))
+        .and_then(|credentials, addr, method: Method, path: FullPath, authenticator: Authenticator| async move {
+            let request = SignedRequest { method: &method, path: path.as_str(), body: &[] };
+            verify(&authenticator, &credentials, addr, &request).await
+        })
+}
+
+// Read the credentials, the address and what is signed of a request
+fn with_credentials() -> impl Filter<Extract = (Credentials, Option<SocketAddr>, Method, FullPath), Error = Rejection> + Clone {
+    warp::header::optional::<String>(AUTHORIZATION.as_str())
+        .and(warp::header::optional::<String>(KEY_ID_HEADER))
+        .and(warp::header::optional::<String>(TIMESTAMP_HEADER))
+        .and(warp::header::optional::<String>(SIGNATURE_HEADER))
+        .map(credentials)
+        .and(tls::remote())
+        .and(warp::method())
+        .and(warp::path::full())
+}
+
+// Authenticate a request, logging and rejecting it when the credentials are not valid
+async fn verify(authenticator: &Authenticator, credentials: &Credentials, addr: Option<SocketAddr>, request: &SignedRequest<'_>) -> Result<ApiKey, Rejection> {
+    authenticator.authenticate(credentials, request).await.map_err(|reason| {
+        log_rejected(credentials.key_id(), addr, reason);
+        warp::reject::custom(Unauthorized)
+    })
+}
+
+// Answer requests without valid credentials with 401, leaving other rejections to warp
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        return Ok(StatusCode::UNAUTHORIZED);
+    }
+
+    Err(rejection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test-secret";
+    const TIMESTAMP: &str = "1700000000";
+
+    fn key(topics: &[&str], user_ids: Option<Vec<usize>>) -> ApiKey {
+        ApiKey {
+            id: "test".to_string(),
+            secret: SECRET.to_string(),
+            topics: topics.iter().map(|t| t.to_string()).collect(),
+            user_ids,
+        }
+    }
+
+    // Sign a request the way a publisher does, as hex of the HMAC of <timestamp>.<method>.<path>.<body>
+    fn sign(secret: &str, timestamp: &str, method: &str, path: &str, body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes any key");
+        mac.update(format!("{}.{}.{}.{}", timestamp, method, path, body).as_bytes());
+        hex_signature(&mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn allows() {
+        let cases = [
+            // (topics, user ids, topic, user id, allowed)
+            (vec!["news"], None, "news", None, true),
+            (vec!["news"], None, "news", Some(1), true),
+            (vec!["news"], None, "sports", None, false),
+            (vec!["*"], None, "sports", None, true),
+            (vec!["news", "sports"], None, "sports", Some(1), true),
+            (vec!["news"], Some(vec![1, 2]), "news", Some(2), true),
+            (vec!["news"], Some(vec![1, 2]), "news", Some(3), false),
+            // Keys limited to users can not broadcast
+            (vec!["news"], Some(vec![1, 2]), "news", None, false),
+            (vec!["*"], Some(vec![1]), "sports", Some(1), true),
+            (vec![], None, "news", None, false),
+        ];
+
+        for (topics, user_ids, topic, user_id, allowed) in cases {
+            let key = key(&topics, user_ids.clone());
+            assert_eq!(key.allows(topic, user_id), allowed, "{:?} {:?} {} {:?}", topics, user_ids, topic, user_id);
+        }
+    }
+
+    #[test]
+    fn decode_hex() {
+        let cases = [
+            // (hex, bytes)
+            ("", Some(vec![])),
+            ("00ff10", Some(vec![0x00, 0xff, 0x10])),
+            ("ABcd", Some(vec![0xab, 0xcd])),
+            ("abc", None),
+            ("zz", None),
+            ("+1", None),
+            ("é1", None),
+        ];
+
+        for (hex, bytes) in cases {
+            assert_eq!(super::decode_hex(hex), bytes, "{}", hex);
+        }
+    }
+
+    #[test]
+    fn check_signature() {
+        let body = br#"{"topic":"news"}"#;
+        let request = SignedRequest { method: &Method::POST, path: "/publish", body };
+        let valid = sign(SECRET, TIMESTAMP, "POST", "/publish", r#"{"topic":"news"}"#);
+
+        let cases = [
+            // (signature, timestamp, accepted)
+            (valid.clone(), TIMESTAMP, true),
+            (valid.to_uppercase(), TIMESTAMP, true),
+            // The timestamp is part of what is signed
+            (valid.clone(), "1700000001", false),
+            (sign("other-secret", TIMESTAMP, "POST", "/publish", r#"{"topic":"news"}"#), TIMESTAMP, false),
+            (sign(SECRET, TIMESTAMP, "GET", "/publish", r#"{"topic":"news"}"#), TIMESTAMP, false),
+            (sign(SECRET, TIMESTAMP, "POST", "/deliveries", r#"{"topic":"news"}"#), TIMESTAMP, false),
+            (sign(SECRET, TIMESTAMP, "POST", "/publish", r#"{"topic":"sports"}"#), TIMESTAMP, false),
+            // The separators can not be moved between the parts
+            (sign(SECRET, TIMESTAMP, "POST./publish", "", r#"{"topic":"news"}"#), TIMESTAMP, false),
+            (valid[..valid.len() - 2].to_string(), TIMESTAMP, false),
+        ];
+
+        for (signature, timestamp, accepted) in cases {
+            let bytes = super::decode_hex(&signature).expect("signature is hex");
+            let result = super::check_signature(SECRET, timestamp, &bytes, &request);
+            assert_eq!(result.is_ok(), accepted, "{} {}", signature, timestamp);
+        }
+    }
+
+    #[test]
+    fn check_timestamp() {
+        let now = 1_700_000_000;
+        let cases = [
+            // (timestamp, accepted)
+            ("1700000000", true),
+            ("1699999700", true),
+            ("1700000300", true),
+            ("1699999699", false),
+            ("1700000301", false),
+            ("0", false),
+            ("-1", false),
+            ("1700000000.5", false),
+            ("", false),
+        ];
+
+        for (timestamp, accepted) in cases {
+            assert_eq!(super::check_timestamp(timestamp, now).is_ok(), accepted, "{}", timestamp);
+        }
+    }
+
+    #[test]
+    fn signature_case_shares_one_record() {
+        assert_eq!(signature_key("ABCDEF"), signature_key("abcdef"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis server on localhost"]
+    async fn rejects_reused_signature() {
+        let authenticator = Authenticator {
+            enabled: true,
+            keys: ApiKeys { keys: vec![key(&["*"], None)] },
+            redis_client: redis::Client::open("redis://127.0.0.1/").expect("redis url is valid"),
+        };
+        let timestamp = (unix_millis() / 1000).to_string();
+        let request = SignedRequest { method: &Method::POST, path: "/publish", body: b"{}" };
+        let credentials = Credentials::Signed {
+            key_id: "test".to_string(),
+            timestamp: timestamp.clone(),
+            signature: sign(SECRET, &timestamp, "POST", "/publish", "{}"),
+        };
+
+        assert!(authenticator.authenticate(&credentials, &request).await.is_ok());
+        assert_eq!(authenticator.authenticate(&credentials, &request).await.err(), Some("signature already used"));
+    }
+}
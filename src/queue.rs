@@ -0,0 +1,420 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::{Clients, StationError, ws};
+use crate::message_receive::Receiver;
+use crate::redis_direct::get_con;
+use crate::station::{advance, from_redis, modify, Media, Station, StationManager};
+
+// Single change to a queue, applied in order to the previous queue
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "op")]
+pub enum QueueOp<'a> {
+    #[serde(rename = "insert")]
+    Insert {
+        #[serde(rename = "index")]
+        index: usize,
+        #[serde(rename = "media")]
+        media: &'a Media,
+    },
+    #[serde(rename = "remove")]
+    Remove {
+        #[serde(rename = "index")]
+        index: usize,
+    },
+    #[serde(rename = "move")]
+    Move {
+        #[serde(rename = "from")]
+        from: usize,
+        #[serde(rename = "to")]
+        to: usize,
+    },
+}
+
+// Changes made to the queue of a station
+#[derive(Debug, Serialize)]
+struct QueueChanged<'a> {
+    #[serde(rename = "stationId")]
+    station_id: Uuid,
+    #[serde(rename = "ops")]
+    ops: Vec<QueueOp<'a>>,
+}
+
+// Full queue of a station, the first item is playing now
+#[derive(Debug, Serialize)]
+struct QueueSnapshot<'a> {
+    #[serde(rename = "stationId")]
+    station_id: Uuid,
+    #[serde(rename = "items")]
+    items: &'a [Media],
+}
+
+// Request for the full queue of a station
+#[derive(Deserialize, Debug)]
+pub struct QueueRequest {
+    #[serde(rename = "stationId")]
+    station_id: Option<Uuid>,
+}
+
+// Change to the queue made by the owner or a DJ
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action")]
+pub enum QueueEdit {
+    // Add media to the end of the queue
+    #[serde(rename = "add")]
+    Add {
+        #[serde(rename = "stationId")]
+        station_id: Option<Uuid>,
+        #[serde(rename = "media")]
+        media: Box<Media>,
+    },
+    // Remove waiting media, index 0 is playing and can only be skipped
+    #[serde(rename = "remove")]
+    Remove {
+        #[serde(rename = "stationId")]
+        station_id: Option<Uuid>,
+        #[serde(rename = "index")]
+        index: usize,
+    },
+    // Move waiting media to another place in the queue
+    #[serde(rename = "move")]
+    Move {
+        #[serde(rename = "stationId")]
+        station_id: Option<Uuid>,
+        #[serde(rename = "from")]
+        from: usize,
+        #[serde(rename = "to")]
+        to: usize,
+    },
+    // Stop the playing media and move on to the next one
+    #[serde(rename = "skip")]
+    Skip {
+        #[serde(rename = "stationId")]
+        station_id: Option<Uuid>,
+    },
+}
+
+impl QueueEdit {
+    fn station_id(&self) -> Option<Uuid> {
+        match self {
+            QueueEdit::Add { station_id, .. } => *station_id,
+            QueueEdit::Remove { station_id, .. } => *station_id,
+            QueueEdit::Move { station_id, .. } => *station_id,
+            QueueEdit::Skip { station_id } => *station_id,
+        }
+    }
+}
+
+// Work out the operations turning the old queue into the new one
+pub fn diff<'a>(old: &[Media], new: &'a [Media]) -> Vec<QueueOp<'a>> {
+    let mut ops = Vec::new();
+
+    // Find which old items are still in the new queue, matching duplicates one to one
+    let mut unmatched: Vec<&Media> = new.iter().collect();
+    let mut removed = Vec::new();
+    for (index, media) in old.iter().enumerate() {
+        match unmatched.iter().position(|m| *m == media) {
+            Some(position) => {
+                unmatched.remove(position);
+            }
+            None => removed.push(index),
+        }
+    }
+
+    // Remove from the back so earlier indexes stay valid
+    let mut current: Vec<&Media> = old.iter().collect();
+    for index in removed.into_iter().rev() {
+        current.remove(index);
+        ops.push(QueueOp::Remove { index });
+    }
+
+    // Walk the new queue, moving kept items into place and inserting new ones
+    for (index, media) in new.iter().enumerate() {
+        if current.get(index) == Some(&media) {
+            continue;
+        }
+
+        match current.iter().skip(index + 1).position(|m| *m == media) {
+            Some(offset) => {
+                let from = index + 1 + offset;
+                let moved = current.remove(from);
+                current.insert(index, moved);
+                ops.push(QueueOp::Move { from, to: index });
+            }
+            None => {
+                current.insert(index, media);
+                ops.push(QueueOp::Insert { index, media });
+            }
+        }
+    }
+
+    ops
+}
+
+// Create the frame telling members how the queue of a station changed
+// Returns None when nothing changed
+pub fn changed_frame(station_id: Uuid, old: &[Media], new: &[Media]) -> Option<String> {
+    let ops = diff(old, new);
+    if ops.is_empty() {
+        return None;
+    }
+
+    let as_json = serde_json::to_string(&QueueChanged { station_id, ops }).unwrap_or_default();
+    Some("queue_changed=".to_string() + &as_json)
+}
+
+// Create the frame holding the full queue of a station
+pub fn snapshot_frame(station_id: Uuid, items: &[Media]) -> String {
+    let as_json = serde_json::to_string(&QueueSnapshot { station_id, items }).unwrap_or_default();
+    "queue=".to_string() + &as_json
+}
+
+// Send the full queue of a station to a single client
+pub async fn send_snapshot(clients: &Clients, id: &str, station_id: Uuid, items: &[Media]) {
+    ws::send_json(clients, id, "queue", &QueueSnapshot { station_id, items }).await;
+}
+
+// Receiver for clients asking for the full queue again
+pub struct QueueReceiver {
+    pub stations: Arc<StationManager>,
+}
+#[async_trait]
+impl Receiver for QueueReceiver {
+    async fn receive_msg(&self, id: &str, msg: &str, clients: &Clients, redis_client: redis::Client) {
+        let request: QueueRequest = match serde_json::from_str(msg) {
+            Ok(v) => v,
+            Err(_) => {
+                ws::send_error(clients, id, "invalid_request", "expected a queue request").await;
+                return;
+            }
+        };
+
+        // Ensure the client is in the station
+        let station_id = match self.stations.resolve_station(id, request.station_id).await {
+            Some(v) => v,
+            None => {
+                ws::send_error(clients, id, "not_in_station", "join the station to see its queue").await;
+                return;
+            }
+        };
+
+        // Establish connection to redis
+        let mut redis_con = match get_con(redis_client).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis");
+                return;
+            }
+        };
+
+        match from_redis(station_id, &mut redis_con).await {
+            Ok(station) => send_snapshot(clients, id, station_id, station.media_queue()).await,
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+// Why a queue edit was not saved
+enum EditError {
+    // The index no longer points at waiting media
+    InvalidIndex,
+    Station(StationError),
+}
+
+impl From<StationError> for EditError {
+    fn from(error: StationError) -> Self {
+        EditError::Station(error)
+    }
+}
+
+// Apply an edit to the latest queue of a station, the playing media at index 0 is left alone
+fn apply_edit(station: &mut Station, request: &QueueEdit, username: &Option<String>) -> Result<(), EditError> {
+    let queue = station.media_queue_mut();
+    match request {
+        QueueEdit::Add { media, .. } => {
+            let mut media = media.as_ref().clone();
+            media.set_queued_by(username.clone());
+            queue.push(media);
+        }
+        QueueEdit::Remove { index, .. } if *index >= 1 && *index < queue.len() => {
+            queue.remove(*index);
+        }
+        QueueEdit::Move { from, to, .. } if *from >= 1 && *to >= 1 && *from < queue.len() && *to < queue.len() => {
+            let media = queue.remove(*from);
+            queue.insert(*to, media);
+        }
+        _ => return Err(EditError::InvalidIndex),
+    }
+
+    Ok(())
+}
+
+// Receiver for the owner and DJs changing the queue and playback
+pub struct QueueEditReceiver {
+    pub stations: Arc<StationManager>,
+}
+#[async_trait]
+impl Receiver for QueueEditReceiver {
+    async fn receive_msg(&self, id: &str, msg: &str, clients: &Clients, redis_client: redis::Client) {
+        let request: QueueEdit = match serde_json::from_str(msg) {
+            Ok(v) => v,
+            Err(_) => {
+                ws::send_error(clients, id, "invalid_request", "expected a queue edit").await;
+                return;
+            }
+        };
+
+        // Ensure the client is in the station
+        let station_id = match self.stations.resolve_station(id, request.station_id()).await {
+            Some(v) => v,
+            None => {
+                ws::send_error(clients, id, "not_in_station", "join the station before editing its queue").await;
+                return;
+            }
+        };
+
+        // Establish connection to redis
+        let mut redis_con = match get_con(redis_client).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis");
+                return;
+            }
+        };
+
+        // Only the owner and DJs of the station may edit the queue
+        let station = match from_redis(station_id, &mut redis_con).await {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+        let username = match clients.read().await.get(id) {
+            Some(client) if station.role_of(client).can_manage() => Some(client.username.clone()),
+            _ => {
+                ws::send_error(clients, id, "forbidden", "only the station owner and DJs can edit the queue").await;
+                return;
+            }
+        };
+
+        // Offsets come from the client, they have to fit inside the media
+        if let QueueEdit::Add { media, .. } = &request {
+            if let Err(reason) = media.check_trim() {
+                ws::send_error(clients, id, "invalid_media", reason).await;
+                return;
+            }
+        }
+
+        // Skipping moves the station on with its own compare and set
+        if let QueueEdit::Skip { .. } = request {
+            if let Some(playing) = station.media_queue().first() {
+                let started_at = self.stations.started_at(station_id).await;
+                advance(station_id, playing, &mut redis_con, started_at, true).await;
+            }
+            return;
+        }
+
+        // The edit is applied to the latest queue, so media which finished in the meantime does not come back
+        // Every instance tells its listeners what changed once the station is written
+        match modify(station_id, &mut redis_con, |station| apply_edit(station, &request, &username)).await {
+            Ok(_) => {}
+            Err(EditError::InvalidIndex) => {
+                ws::send_error(clients, id, "invalid_index", "only waiting media can be moved or removed").await;
+            }
+            Err(EditError::Station(e)) => {
+                eprintln!("could not edit queue: {}", e);
+                ws::send_error(clients, id, "unavailable", "could not save the station").await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Media only told apart by its url
+    fn media(url: &str) -> Media {
+        serde_json::from_value(serde_json::json!({
+            "name": url,
+            "url": url,
+            "durationMs": 1000,
+            "streamingService": "HTTP",
+        })).expect("test media is valid")
+    }
+
+    fn queue(urls: &[&str]) -> Vec<Media> {
+        urls.iter().map(|u| media(u)).collect()
+    }
+
+    // Apply operations the way a client does
+    fn apply(old: &[Media], ops: &[QueueOp]) -> Vec<Media> {
+        let mut current = old.to_vec();
+        for op in ops {
+            match op {
+                QueueOp::Insert { index, media } => current.insert(*index, (*media).clone()),
+                QueueOp::Remove { index } => {
+                    current.remove(*index);
+                }
+                QueueOp::Move { from, to } => {
+                    let moved = current.remove(*from);
+                    current.insert(*to, moved);
+                }
+            }
+        }
+
+        current
+    }
+
+    #[test]
+    fn diff_turns_old_queue_into_new_queue() {
+        // Name, old queue, new queue, most operations expected
+        let cases: &[(&str, &[&str], &[&str], usize)] = &[
+            ("unchanged", &["a", "b", "c"], &["a", "b", "c"], 0),
+            ("empty to empty", &[], &[], 0),
+            ("empty to full", &[], &["a", "b", "c"], 3),
+            ("full to empty", &["a", "b", "c"], &[], 3),
+            ("append", &["a", "b"], &["a", "b", "c"], 1),
+            ("insert in the middle", &["a", "c"], &["a", "b", "c"], 1),
+            ("remove playing", &["a", "b", "c"], &["b", "c"], 1),
+            ("swap", &["a", "b", "c"], &["a", "c", "b"], 1),
+            ("move to front", &["a", "b", "c", "d"], &["d", "a", "b", "c"], 1),
+            ("reverse", &["a", "b", "c", "d"], &["d", "c", "b", "a"], 3),
+            ("duplicate added", &["a", "b"], &["a", "b", "a"], 1),
+            ("one duplicate removed", &["a", "b", "a"], &["a", "b"], 1),
+            ("duplicates reordered", &["a", "a", "b"], &["a", "b", "a"], 1),
+            ("all duplicates", &["a", "a", "a"], &["a", "a"], 1),
+            ("replace everything", &["a", "b"], &["c", "d"], 4),
+            ("mixed", &["a", "b", "c", "d"], &["a", "d", "e", "b"], 3),
+        ];
+
+        for (name, old, new, max_ops) in cases {
+            let old = queue(old);
+            let new = queue(new);
+            let ops = diff(&old, &new);
+
+            assert_eq!(apply(&old, &ops), new, "{}: applying {:?} gives another queue", name, ops);
+            assert!(ops.len() <= *max_ops, "{}: expected at most {} operations, got {:?}", name, max_ops, ops);
+        }
+    }
+
+    #[test]
+    fn diff_only_inserts_into_an_empty_queue() {
+        let new = queue(&["a", "b", "a"]);
+        let ops = diff(&[], &new);
+
+        let expected: Vec<QueueOp> = new.iter().enumerate().map(|(index, media)| QueueOp::Insert { index, media }).collect();
+        assert_eq!(ops, expected);
+    }
+
+    #[test]
+    fn changed_frame_is_none_without_changes() {
+        let station_id = Uuid::nil();
+        let items = queue(&["a", "b"]);
+
+        assert!(changed_frame(station_id, &items, &items).is_none());
+        assert!(changed_frame(station_id, &items, &queue(&["b", "a"])).is_some_and(|f| f.starts_with("queue_changed=")));
+    }
+}
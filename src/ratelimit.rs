@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use warp::{Filter, Rejection, Reply};
+use warp::http::StatusCode;
+use crate::{tls, Limiter};
+
+// Limits used when none are configured, as <name>[:<scope>]=<per second>/<burst>
+// Frames are not limited per address by default, a whole office or campus can share one behind a NAT
+const DEFAULT_LIMITS: &str = "*=10/20,join_station=1/5,station_manage=1/5,station_chat:user=0.5/5,register=5/10,publish=100/200";
+// Rate limited frames within this window count towards a disconnect
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+// Buckets not used for this long are full again and can be dropped
+const IDLE_BUCKET: Duration = Duration::from_secs(600);
+
+// What a limit is counted against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Connection,
+    User,
+    Ip,
+}
+
+impl Scope {
+    fn name(self) -> &'static str {
+        match self {
+            Scope::Connection => "connection",
+            Scope::User => "user",
+            Scope::Ip => "ip",
+        }
+    }
+}
+
+// Sustained rate and burst size of a token bucket
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    per_sec: f64,
+    burst: f64,
+}
+
+// Limits for each receiver id and route, read from a string such as "*=10/20,join_station:ip=50/100"
+// A name without a scope applies to connections and users and * applies to every name
+// Frames are only limited per address by limits with the ip scope, routes are always limited per address
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    limits: HashMap<String, Limit>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        DEFAULT_LIMITS.parse().expect("default rate limits are valid")
+    }
+}
+
+impl FromStr for RateLimits {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut limits = HashMap::new();
+
+        for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, limit) = entry.split_once('=').ok_or(format!("expected <name>=<rate>/<burst> in {}", entry))?;
+            let (per_sec, burst) = limit.split_once('/').ok_or(format!("expected <rate>/<burst> in {}", entry))?;
+            let limit = Limit {
+                per_sec: per_sec.trim().parse().map_err(|_| format!("invalid rate in {}", entry))?,
+                burst: burst.trim().parse().map_err(|_| format!("invalid burst in {}", entry))?,
+            };
+            if limit.per_sec <= 0.0 || limit.burst < 1.0 {
+                return Err(format!("rate must be positive and burst at least 1 in {}", entry));
+            }
+
+            limits.insert(name.trim().to_string(), limit);
+        }
+
+        Ok(RateLimits { limits })
+    }
+}
+
+impl RateLimits {
+    // Find the most specific limit of a frame sent to a receiver for a scope
+    fn frame(&self, name: &str, scope: Scope) -> Option<Limit> {
+        match scope {
+            Scope::Ip => self.find(&[format!("{}:ip", name), "*:ip".to_string()]),
+            scope => self.find(&[format!("{}:{}", name, scope.name()), name.to_string(), format!("*:{}", scope.name()), "*".to_string()]),
+        }
+    }
+
+    // Find the most specific limit of an HTTP route, which only has the address to count against
+    fn route(&self, name: &str) -> Option<Limit> {
+        self.find(&[format!("{}:ip", name), name.to_string(), "*:ip".to_string(), "*".to_string()])
+    }
+
+    // Get the limit of the first key which has one
+    fn find(&self, keys: &[String]) -> Option<Limit> {
+        keys.iter().find_map(|key| self.limits.get(key).copied())
+    }
+}
+
+// Tokens left for a single key, refilled over time
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: Limit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_sec).min(limit.burst);
+        self.updated = now;
+    }
+}
+
+// Rate limited frames of a connection within the current window
+struct Strikes {
+    count: u32,
+    started: Instant,
+}
+
+// What to do with a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    Limited,
+    Disconnect,
+}
+
+// Token bucket rate limiter for websocket frames and HTTP routes
+pub struct RateLimiter {
+    limits: RateLimits,
+    // Rate limited frames within a minute before a connection is closed, 0 never closes it
+    max_strikes: u32,
+    buckets: RwLock<HashMap<String, Bucket>>,
+    strikes: RwLock<HashMap<String, Strikes>>,
+}
+
+impl RateLimiter {
+    // Boilerplate for creating a new instance
+    pub fn new(limits: RateLimits, max_strikes: u32) -> RateLimiter {
+        RateLimiter {
+            limits,
+            max_strikes,
+            buckets: RwLock::new(HashMap::new()),
+            strikes: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // Take a token from the bucket of every key, only if each of them has one left
+    async fn take(&self, limited: &[(String, Limit)]) -> bool {
+        let now = Instant::now();
+        let mut buckets_lock = self.buckets.write().await;
+        for (key, limit) in limited {
+            let bucket = buckets_lock.entry(key.clone()).or_insert(Bucket { tokens: limit.burst, updated: now });
+            bucket.refill(*limit, now);
+            if bucket.tokens < 1.0 {
+                return false;
+            }
+        }
+
+        for (key, _) in limited {
+            if let Some(bucket) = buckets_lock.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        true
+    }
+
+    // Check a frame sent to a receiver, counting strikes against the connection when it is limited
+    pub async fn check_frame(&self, receiver_id: &str, client_id: &str, user_id: usize, ip: Option<IpAddr>) -> Verdict {
+        let mut keys = vec![(Scope::Connection, client_id.to_string()), (Scope::User, user_id.to_string())];
+        if let Some(ip) = ip {
+            keys.push((Scope::Ip, ip.to_string()));
+        }
+        let limited: Vec<(String, Limit)> = keys.iter()
+            .filter_map(|(scope, key)| self.limits.frame(receiver_id, *scope).map(|l| (format!("{}:{}:{}", scope.name(), key, receiver_id), l)))
+            .collect();
+
+        if self.take(&limited).await {
+            return Verdict::Allowed;
+        }
+
+        let now = Instant::now();
+        let mut strikes_lock = self.strikes.write().await;
+        let strikes = strikes_lock.entry(client_id.to_string()).or_insert(Strikes { count: 0, started: now });
+        if now.duration_since(strikes.started) > STRIKE_WINDOW {
+            strikes.count = 0;
+            strikes.started = now;
+        }
+        strikes.count += 1;
+
+        if self.max_strikes > 0 && strikes.count >= self.max_strikes {
+            Verdict::Disconnect
+        } else {
+            Verdict::Limited
+        }
+    }
+
+    // Check a request to an HTTP route from an address
+    pub async fn check_route(&self, route: &str, ip: Option<IpAddr>) -> bool {
+        match (ip, self.limits.route(route)) {
+            (Some(ip), Some(limit)) => self.take(&[(format!("{}:{}:{}", Scope::Ip.name(), ip, route), limit)]).await,
+            _ => true,
+        }
+    }
+
+    // Forget the strikes of a closed connection
+    pub async fn forget(&self, client_id: &str) {
+        self.strikes.write().await.remove(client_id);
+    }
+
+    // Drop buckets and strikes which are no longer needed
+    pub async fn prune(&self) {
+        let now = Instant::now();
+        self.buckets.write().await.retain(|_, b| now.duration_since(b.updated) < IDLE_BUCKET);
+        self.strikes.write().await.retain(|_, s| now.duration_since(s.started) < STRIKE_WINDOW);
+    }
+}
+
+// Rejection for requests over the rate limit of a route
+#[derive(Debug)]
+struct RateLimited;
+impl warp::reject::Reject for RateLimited {}
+
+// Filter rejecting requests to a route once the address sending them is over its limit
+pub fn limit(limiter: Limiter, route: &'static str) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    tls::remote()
+        .and(warp::any().map(move || limiter.clone()))
+        .and_then(move |addr: Option<SocketAddr>, limiter: Limiter| async move {
+            if limiter.check_route(route, addr.map(|a| a.ip())).await {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(RateLimited))
+            }
+        })
+        .untuple_one()
+}
+
+// Answer rate limited requests with 429, leaving other rejections to warp
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<RateLimited>().is_some() {
+        return Ok(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    Err(rejection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refill() {
+        let cases = [
+            // (per second, burst, tokens, elapsed ms, tokens after)
+            (2.0, 10.0, 0.0, 0, 0.0),
+            (2.0, 10.0, 0.0, 500, 1.0),
+            (2.0, 10.0, 3.0, 1000, 5.0),
+            (2.0, 10.0, 9.5, 1000, 10.0),
+            (0.5, 5.0, 0.0, 3000, 1.5),
+            (10.0, 20.0, 0.0, 600_000, 20.0),
+        ];
+
+        for (per_sec, burst, tokens, elapsed_ms, expected) in cases {
+            let start = Instant::now();
+            let mut bucket = Bucket { tokens, updated: start };
+            let now = start + Duration::from_millis(elapsed_ms);
+            bucket.refill(Limit { per_sec, burst }, now);
+
+            assert!((bucket.tokens - expected).abs() < 1e-9, "{} {} {} {}: {}", per_sec, burst, tokens, elapsed_ms, bucket.tokens);
+            assert_eq!(bucket.updated, now);
+        }
+    }
+
+    #[tokio::test]
+    async fn take_stops_at_burst() {
+        let limiter = RateLimiter::new("*=0.001/3".parse().unwrap(), 0);
+        let limited = [("connection:a:test".to_string(), Limit { per_sec: 0.001, burst: 3.0 })];
+
+        let taken = [
+            limiter.take(&limited).await,
+            limiter.take(&limited).await,
+            limiter.take(&limited).await,
+            limiter.take(&limited).await,
+        ];
+        assert_eq!(taken, [true, true, true, false]);
+    }
+
+    #[tokio::test]
+    async fn take_needs_every_bucket() {
+        let limiter = RateLimiter::new("*=0.001/3".parse().unwrap(), 0);
+        let small = ("user:1:test".to_string(), Limit { per_sec: 0.001, burst: 1.0 });
+        let large = ("connection:a:test".to_string(), Limit { per_sec: 0.001, burst: 3.0 });
+
+        assert!(limiter.take(&[small.clone(), large.clone()]).await);
+        assert!(!limiter.take(&[small, large.clone()]).await);
+
+        // The refused frame did not use up a token of the bucket which still had some
+        assert!(limiter.take(std::slice::from_ref(&large)).await);
+        assert!(limiter.take(std::slice::from_ref(&large)).await);
+        assert!(!limiter.take(&[large]).await);
+    }
+
+    #[tokio::test]
+    async fn strikes_disconnect() {
+        let limiter = RateLimiter::new("*=0.001/1".parse().unwrap(), 2);
+
+        let verdicts = [
+            limiter.check_frame("test", "a", 1, None).await,
+            limiter.check_frame("test", "a", 1, None).await,
+            limiter.check_frame("test", "a", 1, None).await,
+        ];
+        assert_eq!(verdicts, [Verdict::Allowed, Verdict::Limited, Verdict::Disconnect]);
+    }
+
+    #[test]
+    fn from_str() {
+        let cases = [
+            // (spec, accepted)
+            ("", true),
+            ("*=10/20", true),
+            ("*=10/20, join_station:ip=50/100 ,", true),
+            ("station_chat:user=0.5/5", true),
+            ("*=10", false),
+            ("*", false),
+            ("*=/20", false),
+            ("*=10/", false),
+            ("*=ten/20", false),
+            ("*=10/twenty", false),
+            ("*=0/20", false),
+            ("*=-1/20", false),
+            ("*=10/0.5", false),
+            ("*=10/20,join_station=1", false),
+        ];
+
+        for (spec, accepted) in cases {
+            assert_eq!(spec.parse::<RateLimits>().is_ok(), accepted, "{}", spec);
+        }
+
+        // The defaults have to parse or the server could not start
+        RateLimits::default();
+    }
+
+    #[test]
+    fn lookup() {
+        let limits: RateLimits = "*=10/20,*:user=8/16,join_station=1/5,join_station:ip=50/100,register=5/10".parse().unwrap();
+
+        let frames = [
+            // (receiver id, scope, burst of the limit used)
+            ("join_station", Scope::Connection, Some(5.0)),
+            ("join_station", Scope::User, Some(5.0)),
+            ("join_station", Scope::Ip, Some(100.0)),
+            ("queue", Scope::Connection, Some(20.0)),
+            ("queue", Scope::User, Some(16.0)),
+            // Addresses are only limited by limits with the ip scope
+            ("queue", Scope::Ip, None),
+            ("register", Scope::Ip, None),
+        ];
+        for (name, scope, burst) in frames {
+            assert_eq!(limits.frame(name, scope).map(|l| l.burst), burst, "{} {:?}", name, scope);
+        }
+
+        let routes = [
+            // (route, burst of the limit used)
+            ("register", Some(10.0)),
+            ("join_station", Some(100.0)),
+            ("publish", Some(20.0)),
+        ];
+        for (name, burst) in routes {
+            assert_eq!(limits.route(name).map(|l| l.burst), burst, "{}", name);
+        }
+    }
+}
@@ -3,7 +3,7 @@ use redis::{AsyncCommands, FromRedisValue};
 use crate::DirectError::{RedisClientError, RedisCMDError, RedisTypeError};
 use crate::RedisError;
 
-type Result<T> = std::result::Result<T, RedisError>;
+pub type Result<T> = std::result::Result<T, RedisError>;
 
 // Establish connection to redis server with client struct
 pub async fn get_con(client: redis::Client) -> Result<Connection> {
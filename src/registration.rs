@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use warp::Reply;
+use warp::http::StatusCode;
+use crate::{Client, Clients, Registrations, Result};
+use crate::publishers::constant_time_eq;
+use crate::timer::unix_millis;
+
+// Structure for expiring registrations which never opened a websocket
+pub struct RegistrationTracker {
+    // Milliseconds a registration waits for its websocket, 0 keeps it forever
+    ttl_ms: u64,
+    // Most registrations without a websocket a single user may have, 0 means no limit
+    max_pending: usize,
+    // Amount of registrations removed because they expired
+    expired: AtomicU64,
+}
+
+impl RegistrationTracker {
+    // Boilerplate for creating a new instance
+    pub fn new(ttl_secs: u64, max_pending: usize) -> RegistrationTracker {
+        RegistrationTracker {
+            ttl_ms: ttl_secs.saturating_mul(1000),
+            max_pending,
+            expired: AtomicU64::new(0),
+        }
+    }
+
+    // Check if a registration waited too long for its websocket
+    pub fn is_expired(&self, client: &Client, now: u64) -> bool {
+        self.ttl_ms > 0 && client.sender.is_none() && now.saturating_sub(client.registered_at) > self.ttl_ms
+    }
+
+    // Check if a user may register another connection
+    pub fn has_room(&self, clients: &HashMap<String, Client>, user_id: usize) -> bool {
+        if self.max_pending == 0 {
+            return true;
+        }
+
+        let now = unix_millis();
+        let pending = clients.values()
+            .filter(|c| c.user_id == user_id && c.sender.is_none() && !self.is_expired(c, now))
+            .count();
+
+        pending < self.max_pending
+    }
+
+    // Remove every expired registration
+    pub async fn sweep(&self, clients: &Clients) {
+        let now = unix_millis();
+        let mut clients_lock = clients.write().await;
+        let before = clients_lock.len();
+        clients_lock.retain(|_, c| !self.is_expired(c, now));
+        let removed = before - clients_lock.len();
+        drop(clients_lock);
+
+        if removed > 0 {
+            self.expired.fetch_add(removed as u64, Ordering::Relaxed);
+            println!("removed {} expired registrations", removed);
+        }
+    }
+
+    pub fn expired(&self) -> u64 {
+        self.expired.load(Ordering::Relaxed)
+    }
+}
+
+// Check if a request may read the metrics, with the configured token or from this machine when there is none
+fn may_read_metrics(authorization: Option<&str>, addr: Option<SocketAddr>, token: Option<&str>) -> bool {
+    match token {
+        Some(token) => authorization
+            .and_then(|a| a.strip_prefix("Bearer "))
+            .is_some_and(|sent| constant_time_eq(sent.trim().as_bytes(), token.as_bytes())),
+        None => addr.is_some_and(|a| a.ip().is_loopback()),
+    }
+}
+
+// Report counters in the Prometheus text format
+pub async fn metrics_handler(authorization: Option<String>, addr: Option<SocketAddr>, token: Option<String>, clients: Clients, registrations: Registrations) -> Result<impl Reply> {
+    if !may_read_metrics(authorization.as_deref(), addr, token.as_deref()) {
+        return Ok(StatusCode::UNAUTHORIZED.into_response());
+    }
+
+    let (connected, pending) = clients.read().await
+        .values()
+        .fold((0, 0), |(connected, pending), c| match c.sender {
+            Some(_) => (connected + 1, pending),
+            None => (connected, pending + 1),
+        });
+
+    Ok(format!(
+        "# TYPE vradio_connections gauge\n\
+         vradio_connections {}\n\
+         # TYPE vradio_registrations_pending gauge\n\
+         vradio_registrations_pending {}\n\
+         # TYPE vradio_registrations_expired_total counter\n\
+         vradio_registrations_expired_total {}\n",
+        connected,
+        pending,
+        registrations.expired(),
+    ).into_response())
+}
@@ -0,0 +1,114 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::from_str;
+use uuid::Uuid;
+use crate::{Clients, StationError, fanout, ws};
+use crate::message_receive::Receiver;
+use crate::redis_direct::get_con;
+use crate::station::{from_redis, modify, Role, StationManager};
+
+// Request from the owner to change the role of a user
+#[derive(Deserialize, Debug)]
+pub struct RoleRequest {
+    #[serde(rename = "stationId")]
+    station_id: Option<Uuid>,
+    #[serde(rename = "userId")]
+    user_id: usize,
+    #[serde(rename = "role")]
+    role: Role,
+}
+
+// Sent to every listener when the role of a user changes
+#[derive(Serialize, Debug)]
+struct RoleChanged {
+    #[serde(rename = "stationId")]
+    station_id: Uuid,
+    #[serde(rename = "userId")]
+    user_id: usize,
+    #[serde(rename = "role")]
+    role: Role,
+}
+
+// Receiver for the owner granting DJ rights and banning users
+pub struct RoleReceiver {
+    pub stations: Arc<StationManager>,
+}
+#[async_trait]
+impl Receiver for RoleReceiver {
+    async fn receive_msg(&self, id: &str, msg: &str, clients: &Clients, redis_client: redis::Client) {
+        let request: RoleRequest = match from_str(msg) {
+            Ok(v) => v,
+            Err(_) => {
+                ws::send_error(clients, id, "invalid_request", "expected a role change").await;
+                return;
+            }
+        };
+
+        // A station has exactly one owner, it can not be granted
+        if request.role == Role::Owner {
+            ws::send_error(clients, id, "invalid_role", "ownership can not be granted").await;
+            return;
+        }
+
+        // The owner may leave out the station when they are only in one
+        let station_id = match request.station_id {
+            Some(v) => v,
+            None => match self.stations.resolve_station(id, None).await {
+                Some(v) => v,
+                None => {
+                    ws::send_error(clients, id, "not_in_station", "say which station to change roles in").await;
+                    return;
+                }
+            },
+        };
+
+        // Establish connection to redis
+        let mut redis_con = match get_con(redis_client).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis");
+                return;
+            }
+        };
+
+        // Only the owner of the station may change roles
+        let station = match from_redis(station_id, &mut redis_con).await {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+        let is_owner = clients.read().await.get(id).is_some_and(|c| station.role_of(c) == Role::Owner);
+        if !is_owner {
+            ws::send_error(clients, id, "forbidden", "only the station owner can change roles").await;
+            return;
+        }
+
+        // Only the roles change, the rest of the station is kept as it is by then
+        let saved = modify(station_id, &mut redis_con, |station| {
+            station.set_role(request.user_id, request.role);
+            Ok::<_, StationError>(())
+        }).await;
+        if let Err(e) = saved {
+            eprintln!("could not change role: {}", e);
+            ws::send_error(clients, id, "unavailable", "could not save the station").await;
+            return;
+        }
+
+        // Tell every listener, then remove a banned user from the station on every instance
+        let mut result = fanout::send_json(&mut redis_con, station_id, "role_changed", &RoleChanged {
+            station_id,
+            user_id: request.user_id,
+            role: request.role,
+        }).await;
+        if request.role == Role::Banned && result.is_ok() {
+            result = fanout::kick(&mut redis_con, station_id, request.user_id).await;
+        }
+
+        if let Err(e) = result {
+            eprintln!("could not announce role change: {}", e);
+        }
+    }
+}
@@ -1,91 +1,804 @@
 use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
 use async_trait::async_trait;
 use redis::aio::Connection;
 use uuid::{Uuid};
-use crate::{Clients};
+use serde_json::Value;
+use crate::{access, changes, fanout, redis_direct, validate, waiting, Client, Clients, StationError, ws};
+use crate::access::JoinRequest;
+use crate::DirectError::RedisCMDError;
 use crate::message_receive::Receiver;
+use crate::presence::{self, Listener};
 use crate::redis_direct::{get_con, get_str};
 use serde::{Serialize, Deserialize};
 use tokio::sync::RwLock;
 use warp::ws::Message;
-use crate::timer::Timer;
+use crate::played::{self, PlayedEntry};
+use crate::queue;
+use crate::timer::{unix_millis, Timer};
+use crate::votes;
+use crate::waiting::WaitingPosition;
+
+// Version of the station format this server writes
+const SCHEMA_VERSION: u64 = 2;
+// Times a change is tried again when someone else keeps writing the station first
+const MAX_WRITE_ATTEMPTS: usize = 5;
 
 // Tell the rust compiler that this value  can be serialized
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct Station {
     // Ensure json keys match
+    #[serde(rename = "schemaVersion", default = "legacy_schema_version")]
+    schema_version: u64,
     #[serde(rename = "id")]
     id: Uuid,
     #[serde(rename = "ownerUsername")]
     owner_username: String,
+    // User id of the owner, missing for stations made before it was recorded
+    #[serde(rename = "ownerUserId", default, skip_serializing_if = "Option::is_none")]
+    owner_user_id: Option<usize>,
 
     #[serde(rename = "name")]
     name: String,
     #[serde(rename = "mediaQueue")]
-    media_queue: Vec<Media>
+    media_queue: Vec<Media>,
+    // Code listeners join with, missing if it was revoked or the station was made by hand
+    #[serde(rename = "joinCode", default, skip_serializing_if = "Option::is_none")]
+    join_code: Option<JoinCode>,
+    // Roles granted by the owner, by user id, users without one are listeners
+    #[serde(rename = "roles", default, skip_serializing_if = "HashMap::is_empty")]
+    roles: HashMap<usize, Role>,
+    // Who may join with the join code
+    #[serde(rename = "visibility", default)]
+    visibility: Visibility,
+    // Argon2 hash of the password of password protected stations
+    #[serde(rename = "passwordHash", default, skip_serializing_if = "Option::is_none")]
+    password_hash: Option<String>,
+    // Users allowed into invite only stations
+    #[serde(rename = "invitedUserIds", default, skip_serializing_if = "Vec::is_empty")]
+    invited_user_ids: Vec<usize>,
+    // Most listeners allowed in at once, the server default is used when missing and 0 means no limit
+    #[serde(rename = "maxListeners", default, skip_serializing_if = "Option::is_none")]
+    max_listeners: Option<usize>,
+    // Media which was at the front of the queue when the station was written and when it started
+    // Lets instances which were not timing the station work out how far into the media it is
+    #[serde(rename = "playing", default, skip_serializing_if = "Option::is_none")]
+    playing: Option<Playing>,
+
+    // Keys added by other services are kept so writing the station back does not erase them
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>
+}
+
+// Start of the media at the front of the queue
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct Playing {
+    #[serde(rename = "url")]
+    url: String,
+    // Unix time in milliseconds the media started playing
+    #[serde(rename = "startedAt")]
+    started_at: u64,
+}
+
+// Human friendly code pointing to a station, stored under join-code:<code> until it expires
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct JoinCode {
+    #[serde(rename = "code")]
+    pub code: String,
+    // Unix time in milliseconds the code stops working
+    #[serde(rename = "expiresAt")]
+    pub expires_at: u64,
+}
+
+// What a user may do in a station
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Role {
+    // Owns the station, found through the owner username rather than stored
+    #[serde(rename = "owner")]
+    Owner,
+    // Runs the queue and playback for the owner
+    #[serde(rename = "dj")]
+    Dj,
+    #[serde(rename = "listener")]
+    Listener,
+    // May not join the station
+    #[serde(rename = "banned")]
+    Banned,
+}
+
+impl Role {
+    // Owners and DJs manage the queue, playback and chat of a station
+    pub fn can_manage(self) -> bool {
+        matches!(self, Role::Owner | Role::Dj)
+    }
+
+    // Check if the role is above another, moderators may only act on users below them
+    pub fn outranks(self, other: Role) -> bool {
+        self.rank() > other.rank()
+    }
+
+    fn rank(self) -> u8 {
+        match self {
+            Role::Owner => 3,
+            Role::Dj => 2,
+            Role::Listener => 1,
+            Role::Banned => 0,
+        }
+    }
+}
+
+// Who may join a station
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum Visibility {
+    // Listed for everyone and joinable with the code
+    #[serde(rename = "public")]
+    Public,
+    // Only joinable with the code, the default for stations made before visibility existed
+    #[default]
+    #[serde(rename = "unlisted")]
+    Unlisted,
+    // Joinable with the code and the password
+    #[serde(rename = "password")]
+    Password,
+    // Joinable with the code by invited users only
+    #[serde(rename = "invite_only")]
+    InviteOnly,
+}
+
+// How many stations a connection may be in at once
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum StationMode {
+    // Joining a station leaves the previous one
+    #[serde(rename = "single")]
+    Single,
+    // Stations are joined side by side, frames tell them apart by their stationId
+    #[serde(rename = "multi")]
+    Multi,
+}
+
+impl FromStr for StationMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "single" => Ok(StationMode::Single),
+            "multi" => Ok(StationMode::Multi),
+            _ => Err(format!("unknown station mode {}", value)),
+        }
+    }
+}
+
+// Stations written before versioning was added are version 1
+fn legacy_schema_version() -> u64 {
+    1
 }
 
 // Tell the rust compiler that this value  can be serialized
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Media {
     // Ensure json keys match
     #[serde(rename = "name")]
     name: String,
     #[serde(rename = "url")]
     url: String,
-    #[serde(rename = "duration")]
+    // Duration in seconds, kept for older clients
+    #[serde(rename = "duration", default)]
     duration: i64,
+    // Precise duration in milliseconds, preferred over duration when present
+    #[serde(rename = "durationMs", default, skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
     #[serde(rename = "streamingService")]
-    service: StreamingService
+    service: StreamingService,
+    // Details the player of the streaming service needs
+    #[serde(rename = "serviceMetadata", default, skip_serializing_if = "Option::is_none")]
+    service_metadata: Option<ServiceMetadata>,
+
+    #[serde(rename = "artworkUrl", default, skip_serializing_if = "Option::is_none")]
+    artwork_url: Option<String>,
+    #[serde(rename = "artist", default, skip_serializing_if = "Option::is_none")]
+    artist: Option<String>,
+    #[serde(rename = "album", default, skip_serializing_if = "Option::is_none")]
+    album: Option<String>,
+    // Milliseconds into the media playback starts at
+    #[serde(rename = "startOffset", default, skip_serializing_if = "Option::is_none")]
+    start_offset: Option<u64>,
+    // Milliseconds cut off the end of the media
+    #[serde(rename = "endTrim", default, skip_serializing_if = "Option::is_none")]
+    end_trim: Option<u64>,
+    // Username of the account which queued the media
+    #[serde(rename = "queuedBy", default, skip_serializing_if = "Option::is_none")]
+    queued_by: Option<String>,
+
+    // Keys added by other services are kept as they are
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>
 }
 
-// Tell the rust compiler that this value  can be serialized
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+// Streaming service a media is played from
+// Unknown services are kept as they are so stations using them still load
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(from = "String", into = "String")]
 pub enum StreamingService {
-    // Ensure json keys match
-    #[serde(rename = "SPOTIFY")]
     Spotify,
-    #[serde(rename = "NETFLIX")]
-    Netflix
+    Netflix,
+    YouTube,
+    SoundCloud,
+    AppleMusic,
+    Twitch,
+    // Audio file or stream served directly over http
+    Http,
+    Other(String)
+}
+
+impl From<String> for StreamingService {
+    fn from(value: String) -> Self {
+        // Ensure json values match
+        match value.as_str() {
+            "SPOTIFY" => StreamingService::Spotify,
+            "NETFLIX" => StreamingService::Netflix,
+            "YOUTUBE" => StreamingService::YouTube,
+            "SOUNDCLOUD" => StreamingService::SoundCloud,
+            "APPLE_MUSIC" => StreamingService::AppleMusic,
+            "TWITCH" => StreamingService::Twitch,
+            "HTTP" => StreamingService::Http,
+            _ => StreamingService::Other(value),
+        }
+    }
+}
+
+impl From<StreamingService> for String {
+    fn from(value: StreamingService) -> Self {
+        match value {
+            StreamingService::Spotify => "SPOTIFY".to_string(),
+            StreamingService::Netflix => "NETFLIX".to_string(),
+            StreamingService::YouTube => "YOUTUBE".to_string(),
+            StreamingService::SoundCloud => "SOUNDCLOUD".to_string(),
+            StreamingService::AppleMusic => "APPLE_MUSIC".to_string(),
+            StreamingService::Twitch => "TWITCH".to_string(),
+            StreamingService::Http => "HTTP".to_string(),
+            StreamingService::Other(v) => v,
+        }
+    }
+}
+
+// Service specific details of a media
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ServiceMetadata {
+    // Id of the track, video or channel on the service
+    #[serde(rename = "trackId", default, skip_serializing_if = "Option::is_none")]
+    track_id: Option<String>,
+    // Milliseconds into the source the service should start playing from
+    #[serde(rename = "startOffset", default, skip_serializing_if = "Option::is_none")]
+    start_offset: Option<u64>,
+    // Any other keys the service uses are kept as they are
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Station {
+    // Create an empty station at the current schema version
+    pub fn new(owner_username: &str, owner_user_id: usize, name: &str) -> Station {
+        Station {
+            schema_version: SCHEMA_VERSION,
+            id: Uuid::new_v4(),
+            owner_username: owner_username.to_string(),
+            owner_user_id: Some(owner_user_id),
+            name: name.to_string(),
+            media_queue: Vec::new(),
+            join_code: None,
+            roles: HashMap::new(),
+            visibility: Visibility::default(),
+            password_hash: None,
+            invited_user_ids: Vec::new(),
+            max_listeners: None,
+            playing: None,
+            extra: serde_json::Map::new(),
+        }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    // Get the unix time in milliseconds the media at the front of the queue started playing, if it was recorded
+    pub fn started_at(&self) -> Option<u64> {
+        match (&self.playing, self.media_queue.first()) {
+            (Some(playing), Some(media)) if playing.url == media.url => Some(playing.started_at),
+            _ => None,
+        }
+    }
+
+    pub fn owner_username(&self) -> &str {
+        &self.owner_username
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn rename(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    pub fn join_code(&self) -> Option<&JoinCode> {
+        self.join_code.as_ref()
+    }
+
+    // Replace the join code, returns the previous one
+    pub fn set_join_code(&mut self, join_code: Option<JoinCode>) -> Option<JoinCode> {
+        std::mem::replace(&mut self.join_code, join_code)
+    }
+
+    // Work out the role of a client in the station
+    // The username and user id come from the token the client registered with, so they can not be made up
+    pub fn role_of(&self, client: &Client) -> Role {
+        self.role_of_user(&client.username, client.user_id)
+    }
+
+    // Work out the role of a user known from their token
+    pub fn role_of_user(&self, username: &str, user_id: usize) -> Role {
+        if username == self.owner_username {
+            return Role::Owner;
+        }
+
+        self.roles.get(&user_id).copied().unwrap_or(Role::Listener)
+    }
+
+    // Work out the role of a user known only by their id
+    // Owners of stations made before the owner id was recorded are only found through role_of
+    pub fn role_of_id(&self, user_id: usize) -> Role {
+        if self.owner_user_id == Some(user_id) {
+            return Role::Owner;
+        }
+
+        self.roles.get(&user_id).copied().unwrap_or(Role::Listener)
+    }
+
+    // Grant a role to a user, listeners are not stored
+    pub fn set_role(&mut self, user_id: usize, role: Role) {
+        match role {
+            Role::Listener => self.roles.remove(&user_id),
+            role => self.roles.insert(user_id, role),
+        };
+    }
+
+    pub fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+
+    pub fn password_hash(&self) -> Option<&str> {
+        self.password_hash.as_deref()
+    }
+
+    // Change who may join, the password hash is only kept for password protected stations
+    pub fn set_visibility(&mut self, visibility: Visibility, password_hash: Option<String>) {
+        self.visibility = visibility;
+        self.password_hash = match visibility {
+            Visibility::Password => password_hash,
+            _ => None,
+        };
+    }
+
+    pub fn is_invited(&self, user_id: usize) -> bool {
+        self.invited_user_ids.contains(&user_id)
+    }
+
+    // Allow a user into the station when it is invite only
+    pub fn invite(&mut self, user_id: usize) {
+        if !self.is_invited(user_id) {
+            self.invited_user_ids.push(user_id);
+        }
+    }
+
+    pub fn uninvite(&mut self, user_id: usize) {
+        self.invited_user_ids.retain(|u| *u != user_id);
+    }
+
+    pub fn max_listeners(&self) -> Option<usize> {
+        self.max_listeners
+    }
+
+    pub fn set_max_listeners(&mut self, max_listeners: Option<usize>) {
+        self.max_listeners = max_listeners;
+    }
+
+    pub fn media_queue(&self) -> &[Media] {
+        &self.media_queue
+    }
+
+    pub fn media_queue_mut(&mut self) -> &mut Vec<Media> {
+        &mut self.media_queue
+    }
+
+    // Add media to the end of the queue
+    pub fn enqueue(&mut self, media: Media) {
+        self.media_queue.push(media);
+    }
+
+    // Check if a media is waiting in the queue behind the one playing
+    pub fn has_pending(&self, url: &str) -> bool {
+        self.media_queue.iter().skip(1).any(|m| m.url == url)
+    }
+
+    // Sort the media waiting behind the one playing by score, highest first
+    // Returns true if the order changed
+    pub fn reorder_pending(&mut self, scores: &HashMap<String, i64>) -> bool {
+        if self.media_queue.len() < 3 {
+            return false;
+        }
+
+        let score = |m: &Media| scores.get(&m.url).copied().unwrap_or(0);
+        let sorted = self.media_queue[1..].windows(2).all(|w| score(&w[0]) >= score(&w[1]));
+        if sorted {
+            return false;
+        }
+
+        // Sort is stable so items with the same score keep their order
+        self.media_queue[1..].sort_by_key(|m| std::cmp::Reverse(score(m)));
+
+        true
+    }
+}
+
+impl Media {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn set_queued_by(&mut self, username: Option<String>) {
+        self.queued_by = username;
+    }
+
+    // Full duration of the media in milliseconds
+    pub fn duration_ms(&self) -> u64 {
+        self.duration_ms.unwrap_or((self.duration.max(0) as u64).saturating_mul(1000))
+    }
+
+    // Milliseconds into the media playback starts at
+    pub fn start_offset(&self) -> u64 {
+        self.start_offset
+            .or_else(|| self.service_metadata.as_ref().and_then(|m| m.start_offset))
+            .unwrap_or(0)
+    }
+
+    // Milliseconds the media plays for once the start offset and end trim are applied
+    pub fn play_length_ms(&self) -> u64 {
+        let end = self.duration_ms().saturating_sub(self.end_trim.unwrap_or(0));
+
+        end.saturating_sub(self.start_offset())
+    }
+
+    // Ensure the start offset and end trim leave something of the media to play
+    pub fn check_trim(&self) -> Result<(), &'static str> {
+        let trimmed = self.start_offset().checked_add(self.end_trim.unwrap_or(0));
+        match trimmed {
+            Some(trimmed) if trimmed < self.duration_ms() => Ok(()),
+            _ => Err("the start offset and end trim must leave part of the media to play"),
+        }
+    }
+}
+
+// Bring raw station json from an older version up to the current version
+fn migrate(mut value: Value) -> Value {
+    let version = value.get("schemaVersion").and_then(Value::as_u64).unwrap_or(1);
+
+    if version < 2 {
+        value = migrate_v1(value);
+    }
+
+    value
+}
+
+// Version 2 stores precise media durations in milliseconds
+fn migrate_v1(mut value: Value) -> Value {
+    if let Some(queue) = value.get_mut("mediaQueue").and_then(Value::as_array_mut) {
+        for media in queue.iter_mut().filter_map(Value::as_object_mut) {
+            if !media.contains_key("durationMs") {
+                if let Some(duration) = media.get("duration").and_then(Value::as_i64) {
+                    media.insert("durationMs".to_string(), Value::from(duration.max(0).saturating_mul(1000)));
+                }
+            }
+        }
+    }
+
+    if let Some(station) = value.as_object_mut() {
+        station.insert("schemaVersion".to_string(), Value::from(2));
+    }
+
+    value
+}
+
+// Fill in the duration in seconds of media which only has a duration in milliseconds
+// Older clients only read the seconds, so a media written with durationMs alone must not say 0
+fn with_second_durations(mut value: Value) -> Value {
+    if let Some(queue) = value.get_mut("mediaQueue").and_then(Value::as_array_mut) {
+        for media in queue.iter_mut().filter_map(Value::as_object_mut) {
+            if let Some(duration_ms) = media.get("durationMs").and_then(Value::as_u64) {
+                media.insert("duration".to_string(), Value::from(duration_ms.div_ceil(1000)));
+            }
+        }
+    }
+
+    value
+}
+
+// Record when the media at the front of the queue started, keeping the time if it was playing already
+fn with_playing(mut value: Value, now: u64) -> Value {
+    let head = value.pointer("/mediaQueue/0/url").and_then(Value::as_str).map(str::to_string);
+    let playing = value.pointer("/playing/url").and_then(Value::as_str).map(str::to_string);
+
+    if let Some(station) = value.as_object_mut() {
+        match head {
+            Some(head) if playing.as_ref() == Some(&head) => {}
+            Some(head) => {
+                let playing = serde_json::to_value(Playing { url: head, started_at: now }).unwrap_or_default();
+                station.insert("playing".to_string(), playing);
+            }
+            None => {
+                station.remove("playing");
+            }
+        }
+    }
+
+    value
 }
-pub async fn from_redis(id: Uuid, redis_connection: &mut Connection) -> Option<Station> {
+
+pub async fn from_redis(id: Uuid, redis_connection: &mut Connection) -> Result<Station, StationError> {
     // Construct key for redis
-    let station_key = &("Station_".to_owned() + &id.to_string());
+    let station_key = "Station_".to_owned() + &id.to_string();
     // Get raw value from redis
-    let from_redis = match get_str(redis_connection, station_key).await {
-        Ok(v) => v,
-        Err(_) => return None,
+    let from_redis: Option<String> = redis::cmd("GET")
+        .arg(&station_key)
+        .query_async(redis_connection)
+        .await
+        .map_err(|e| StationError::Redis(station_key.clone(), RedisCMDError(e).into()))?;
+    let from_redis = match from_redis {
+        Some(v) => v,
+        None => return Err(StationError::NotFound(station_key)),
     };
 
-    // Convert raw value into station structure
-    let to_json: Station = match serde_json::from_str(&from_redis) {
-        Ok(v) => v,
-        Err(_) => return None,
-    };
+    // Parse the raw value and bring it up to the current version
+    let value: Value = serde_json::from_str(&from_redis)
+        .map_err(|e| StationError::Decode(station_key.clone(), String::new(), e))?;
+    let value = migrate(value);
+
+    // Convert value into station structure, keeping the path of any error
+    let to_json: Station = serde_path_to_error::deserialize(value)
+        .map_err(|e| StationError::Decode(station_key.clone(), e.path().to_string(), e.into_inner()))?;
+
+    if to_json.schema_version > SCHEMA_VERSION {
+        eprintln!("{} uses newer schema version {}, unknown keys are kept as they are", station_key, to_json.schema_version);
+    }
 
     // Return said structure
-    return Some(to_json);
+    Ok(to_json)
 }
 
-pub async fn to_redis(station: &Station, redis_connection: &mut Connection) {
+// Write a station to redis, returns false if it could not be written
+pub async fn to_redis(station: &Station, redis_connection: &mut Connection) -> bool {
     // Construct key for redis
     let station_key = &("Station_".to_owned() + &station.id.to_string());
 
     // Convert provided station into a json string
-    if let Ok(to_json) = serde_json::to_string(&station) {
+    if let Some(to_json) = to_json(station) {
         // Create set command
-        let _ = redis::cmd("SET")
+        let result = redis::cmd("SET")
             // Add key as first argument
             .arg(station_key)
             // Add json as second argument
             .arg(to_json)
             // execute command
-            .query_async::<_, Option<String>>(redis_connection)
+            .query_async::<_, ()>(redis_connection)
             // Wait for redis server
-            .await
-            .expect("Could not set station");
+            .await;
+
+        // Let every instance reload the station and tell its listeners what changed
+        match result {
+            Ok(_) => {
+                changes::notify(redis_connection, station.id).await;
+                true
+            }
+            Err(e) => {
+                eprintln!("Could not set station: {}", e);
+                false
+            }
+        }
     } else {
-        eprintln!("Could not serialize station")
+        eprintln!("Could not serialize station");
+        false
+    }
+}
+
+// Convert a station into the json stored in redis
+fn to_json(station: &Station) -> Option<String> {
+    serde_json::to_value(station)
+        .and_then(|v| serde_json::to_string(&with_playing(with_second_durations(v), unix_millis())))
+        .ok()
+}
+
+// Load a station and watch it, write_watched then only writes it if nobody else wrote it in between
+pub async fn watch(id: Uuid, redis_connection: &mut Connection) -> Result<Station, StationError> {
+    let station_key = "Station_".to_owned() + &id.to_string();
+    redis::cmd("WATCH")
+        .arg(&station_key)
+        .query_async::<_, ()>(redis_connection)
+        .await
+        .map_err(|e| StationError::Redis(station_key, RedisCMDError(e).into()))?;
+
+    let station = from_redis(id, redis_connection).await;
+    if station.is_err() {
+        unwatch(redis_connection).await;
+    }
+
+    station
+}
+
+// Stop watching a station which is not going to be written
+pub async fn unwatch(redis_connection: &mut Connection) {
+    if let Err(e) = redis::cmd("UNWATCH").query_async::<_, ()>(redis_connection).await {
+        eprintln!("could not unwatch station: {}", e);
+    }
+}
+
+// Write a station loaded with watch, returns false if someone else wrote it first
+pub async fn write_watched(station: &Station, redis_connection: &mut Connection) -> Result<bool, StationError> {
+    let station_key = "Station_".to_owned() + &station.id.to_string();
+    let to_json = match to_json(station) {
+        Some(v) => v,
+        None => {
+            unwatch(redis_connection).await;
+            return Err(StationError::NotSaved(station_key));
+        }
+    };
+
+    // The transaction is dropped by redis when the watched station changed
+    let written: Option<()> = redis::pipe()
+        .atomic()
+        .cmd("SET").arg(&station_key).arg(to_json).ignore()
+        .query_async(redis_connection)
+        .await
+        .map_err(|e| StationError::Redis(station_key, RedisCMDError(e).into()))?;
+
+    // Let every instance reload the station and tell its listeners what changed
+    if written.is_some() {
+        changes::notify(redis_connection, station.id).await;
+    }
+
+    Ok(written.is_some())
+}
+
+// Load a station, change it and write it back, starting over from a fresh copy when someone else wrote it first
+// The change runs again on every attempt so it is applied on top of the latest station, an error from it stops without writing
+// Returns the station as written together with what the change returned
+pub async fn modify<T, E: From<StationError>>(station_id: Uuid, redis_connection: &mut Connection, mut change: impl FnMut(&mut Station) -> Result<T, E>) -> Result<(Station, T), E> {
+    for _ in 0..MAX_WRITE_ATTEMPTS {
+        let mut station = watch(station_id, redis_connection).await?;
+
+        let changed = match change(&mut station) {
+            Ok(v) => v,
+            Err(e) => {
+                unwatch(redis_connection).await;
+                return Err(e);
+            }
+        };
+
+        if write_watched(&station, redis_connection).await? {
+            return Ok((station, changed));
+        }
+    }
+
+    eprintln!("gave up changing station {} after {} attempts", station_id, MAX_WRITE_ATTEMPTS);
+    Err(StationError::NotSaved(station_id.to_string()).into())
+}
+
+// Move a station on to the next media in its queue and save it, returns false if it was not moved on
+// Nothing happens once the given media stopped playing, so instances and voters racing to move on only skip it once
+// Every instance restarts playback once it sees the change
+pub async fn advance(station_id: Uuid, playing: &Media, redis_connection: &mut Connection, started_at: Option<u64>, skipped: bool) -> bool {
+    let mut finished = None;
+
+    for _ in 0..MAX_WRITE_ATTEMPTS {
+        let mut station = match watch(station_id, redis_connection).await {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("{}", e);
+                return false;
+            }
+        };
+
+        if station.media_queue.first() != Some(playing) {
+            unwatch(redis_connection).await;
+            return false;
+        }
+
+        // Remove media from the queue, the next media starts now even if it is the same one again
+        let media = station.media_queue.remove(0);
+        station.playing = None;
+
+        // Update station in redis, trying again if it was written in the meantime
+        match write_watched(&station, redis_connection).await {
+            Ok(true) => {
+                finished = Some(media);
+                break;
+            }
+            Ok(false) => continue,
+            Err(e) => {
+                eprintln!("{}", e);
+                return false;
+            }
+        }
+    }
+
+    let finished = match finished {
+        Some(v) => v,
+        None => {
+            eprintln!("gave up moving station {} on after {} attempts", station_id, MAX_WRITE_ATTEMPTS);
+            return false;
+        }
+    };
+
+    // Votes for the finished media no longer count
+    votes::clear(redis_connection, station_id, &finished).await;
+
+    // Keep the finished media in the played list of the station
+    played::record(redis_connection, station_id, &PlayedEntry {
+        media: finished,
+        started_at,
+        ended_at: unix_millis(),
+        skipped,
+    }).await;
+
+    true
+}
+
+// Frame body referring to a station
+#[derive(Debug, Serialize)]
+pub struct StationRef {
+    #[serde(rename = "stationId")]
+    pub station_id: Uuid,
+}
+
+// Playback state of a station, stamped with the server time so clients can stay in sync
+#[derive(Debug, Serialize)]
+pub struct PlaybackFrame<'a> {
+    #[serde(rename = "stationId")]
+    station_id: Uuid,
+    // Media playing now, missing if the queue is empty or the frame is a tick
+    #[serde(rename = "media", skip_serializing_if = "Option::is_none")]
+    media: Option<&'a Media>,
+    // Unix time in milliseconds the frame was created at
+    #[serde(rename = "serverTime")]
+    server_time: u64,
+    // Milliseconds the media has been playing for
+    #[serde(rename = "elapsedMs")]
+    elapsed_ms: u64,
+    // Milliseconds into the media the client should be at when serverTime was taken
+    #[serde(rename = "positionMs", skip_serializing_if = "Option::is_none")]
+    position_ms: Option<u64>,
+}
+
+impl<'a> PlaybackFrame<'a> {
+    // Describe how far into the playing media a station is
+    pub fn new(station_id: Uuid, playing: Option<&'a Media>, elapsed_ms: u64) -> PlaybackFrame<'a> {
+        PlaybackFrame {
+            station_id,
+            media: playing,
+            server_time: unix_millis(),
+            elapsed_ms,
+            position_ms: playing.map(|m| m.start_offset().saturating_add(elapsed_ms)),
+        }
+    }
+
+    // Leave out the media, used for ticks where the client already knows it
+    pub fn without_media(mut self) -> PlaybackFrame<'a> {
+        self.media = None;
+        self
+    }
+
+    // Convert to json with a key to indicate what the message is
+    pub fn to_frame(&self, key: &str) -> String {
+        key.to_string() + "=" + &serde_json::to_string(self).unwrap_or_default()
     }
 }
 
@@ -93,7 +806,13 @@ pub async fn to_redis(station: &Station, redis_connection: &mut Connection) {
 pub struct StationManager {
     pub stations: RwLock<HashMap<Uuid, Vec<String>>>,
     // Store time for each station
-    timers: RwLock<HashMap<Uuid, Timer>>
+    timers: RwLock<HashMap<Uuid, Timer>>,
+    // Queue of each station as last told to its local members, used to work out what changed
+    queues: RwLock<HashMap<Uuid, Vec<Media>>>,
+    // Local clients waiting to get into full stations
+    waiting: RwLock<HashMap<Uuid, Vec<String>>>,
+    // Most listeners allowed in a station which does not set its own limit, 0 means no limit
+    default_max_listeners: usize,
 }
 
 // Handle join requests for stations
@@ -109,18 +828,24 @@ impl Receiver for StationManager {
             },
         };
 
-        let mut string_msg = msg.to_string().clone();
-
-        // If message ends in a new line remove it
-        if string_msg.chars().last().unwrap() == '\n' {
-            string_msg.truncate(string_msg.len() - 1);
+        // Read the join code and any credentials sent with it
+        let request = match JoinRequest::parse(msg) {
+            Some(v) => v,
+            None => {
+                ws::send_error(clients, id, "invalid_request", "expected a join code or join request").await;
+                return;
+            }
+        };
+        if !validate::valid_join_code(&request.code) {
+            ws::send_error(clients, id, "invalid_code", "join codes only use letters and digits").await;
+            return;
         }
 
         // Get the station id from join code through redis
-        let result = match get_str(&mut redis_con, &("join-code:".to_owned() + &string_msg)).await {
+        let result = match get_str(&mut redis_con, &("join-code:".to_owned() + &request.code)).await {
             Ok(v) => v,
             Err(_) => {
-                eprintln!("unable to find key");
+                ws::send_error(clients, id, "unknown_code", "no station uses that join code").await;
                 return;
             }
         };
@@ -130,59 +855,100 @@ impl Receiver for StationManager {
             Ok(u) => u,
             Err(_) => {
                 eprintln!("invalid uuid");
+                ws::send_error(clients, id, "unavailable", "the station could not be loaded").await;
                 return;
             }  
         };
         // Get station from redis
         let station = match from_redis(station_id, &mut redis_con).await {
-            Some(v) => v,
-            None => {
-                eprintln!("Could not find station");
+            Ok(v) => v,
+            // The code outlived the station it pointed to
+            Err(StationError::NotFound(_)) => {
+                ws::send_error(clients, id, "not_found", "the station no longer exists").await;
+                return;
+            }
+            Err(e) => {
+                eprintln!("Could not find station: {}", e);
+                ws::send_error(clients, id, "unavailable", "the station could not be loaded").await;
                 return;
             }
         };
 
-        // Add user to station
-        self.join_station(station_id, id).await;
-
-        // Check if a station is currently playing something
-        if let Some(currently_playing) = station.media_queue.get(0) {
-            // Get read lock on clients
-            let clients_lock = clients.read().await;
-            // Get client with id
-            let client = match clients_lock.get(id) {
-                Some(v) => v,
-                None => return,
-            };
+        // Copy the client so the clients lock is not held while checking the password
+        let client = match clients.read().await.get(id) {
+            Some(v) => v.clone(),
+            None => return,
+        };
+        let listener = client.listener();
 
-            // Check if client has a sender
-            if let Some(sender) = &client.sender {
-                // Convert the currently playing media to json
-                let as_json = match serde_json::to_string(currently_playing) {
-                    Ok(v) => v,
-                    Err(_) => return,
-                };
+        // Ensure the client may join
+        if let Err(denied) = access::admit(&mut redis_con, &station, &client.username, client.user_id, request.password.as_deref()).await {
+            ws::send_error(clients, id, denied.code(), denied.message()).await;
+            return;
+        }
 
-                // Send the json with a key to indicate what the message is
-                let _ = sender.send(Ok(Message::text("playing=".to_string() + &as_json)));
+        // In single station mode joining a station leaves the previous one
+        if client.station_mode == StationMode::Single {
+            for left in self.leave_except(id, Some(station_id), &listener, &mut redis_con).await {
+                ws::send_json(clients, id, "left", &StationRef { station_id: left }).await;
             }
         }
-    }
-}
 
-impl StationManager {
-    // Boilerplate for creating a new instance
-    pub fn new() -> StationManager {
-        StationManager {
-            stations: RwLock::new(HashMap::new()),
-            timers: RwLock::new(HashMap::new()),
+        // Full stations send new listeners to the waiting room, the owner and DJs skip it
+        let already_joined = self.joined_stations(id).await.contains(&station_id);
+        if !already_joined && !station.role_of(&client).can_manage() {
+            match self.claim_place(&mut redis_con, &station, id, &listener).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.wait(station_id, id, clients, &mut redis_con).await;
+                    return;
+                }
+                Err(e) => eprintln!("could not check station capacity: {}", e),
+            }
         }
+
+        self.complete_join(&station, id, &listener, clients, &mut redis_con).await;
     }
 
-    // Update loop for stations
-    pub async fn update_clients(&self, clients: &Clients, redis_client: redis::Client) {
-        // Obtain redis connection
+    // Remove a disconnected client from its stations and waiting rooms
+    async fn client_disconnected(&self, id: &str, clients: &Clients, redis_client: redis::Client) {
+        let listener = match clients.read().await.get(id) {
+            Some(v) => v.listener(),
+            None => return,
+        };
+
+        // Establish connection to redis
         let mut redis_con: Connection = match get_con(redis_client).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis");
+                return;
+            },
+        };
+
+        self.leave_except(id, None, &listener, &mut redis_con).await;
+    }
+}
+
+impl StationManager {
+    // Boilerplate for creating a new instance
+    pub fn new(default_max_listeners: usize) -> StationManager {
+        StationManager {
+            stations: RwLock::new(HashMap::new()),
+            timers: RwLock::new(HashMap::new()),
+            queues: RwLock::new(HashMap::new()),
+            waiting: RwLock::new(HashMap::new()),
+            default_max_listeners,
+        }
+    }
+
+    // Update loop for stations
+    pub async fn update_clients(&self, clients: &Clients, redis_client: redis::Client) {
+        // Stations which no longer exist, removed once the loop is done
+        let mut deleted = Vec::new();
+
+        // Obtain redis connection
+        let mut redis_con: Connection = match get_con(redis_client.clone()).await {
             Ok(v) => v,
             Err(_) => {
                 eprintln!("Could not connect to redis");
@@ -198,111 +964,755 @@ impl StationManager {
         // Loop through stations
         for (station_id, joined_clients) in &*stations_lock {
             // Get each station from redis
-            let mut station = match from_redis(station_id.clone(), &mut redis_con).await {
-                Some(v) => v,
-                None => {
-                    eprintln!("Could not load station");
+            let station = match from_redis(*station_id, &mut redis_con).await {
+                Ok(v) => v,
+                Err(StationError::NotFound(_)) => {
+                    deleted.push(*station_id);
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Could not load station: {}", e);
                     continue;
                 }
             };
 
             // Check if the queue is not empty
-            if station.media_queue.len() != 0 {
+            if !station.media_queue.is_empty() {
                 // Get the time of the station
-                let current_time = match timers_lock.get(station_id) {
-                    Some(v) => match v.get_time() {
+                let current_millis = match timers_lock.get(station_id) {
+                    Some(v) => match v.get_millis() {
                         Ok(v) => v,
                         Err(_) => {
                             eprintln!("Could not get time");
 
-                            1
+                            1000
                         },
                     },
                     None => {
-                        let new_timer = Timer::new();
-                        timers_lock.insert(station_id.clone(), new_timer);
+                        // Continue from where the instance which started the media is
+                        let new_timer = station.started_at().map(Timer::since).unwrap_or_else(Timer::new);
+                        let current_millis = new_timer.get_millis().unwrap_or(0);
+                        timers_lock.insert(*station_id, new_timer);
 
-                        0
+                        current_millis
                     },
                 };
 
                 // Get the currently playing media
-                let currently_playing = match station.media_queue.get(0) {
+                let currently_playing = match station.media_queue.first() {
                     Some(v) => v,
                     None => {
                         eprintln!("Could not find first media in queue");
                         continue;
                     },
                 };
-                let mut as_json: String = String::new();
-
-                // Check if the time exceeds the duration of the currently playing media
-                if currently_playing.duration <= current_time as i64 {
-                    // Remove media from the queue
-                    station.media_queue.remove(0);
-
-                    // Update station in redis
-                    to_redis(&station, &mut redis_con).await;
-
-                    // Check if there is another media in the queue
-                    if let Some(new_play) = station.media_queue.get(0) {
-                        as_json = match serde_json::to_string(new_play) {
-                            Ok(v) => v,
-                            Err(_) => {
-                                eprintln!("Could not serialize media");
-                                continue;
-                            },
-                        }
-                    } else {
-                        // Otherwise remove timer and continue
-                        timers_lock.remove(station_id);
-                        continue;
-                    }
 
+                // Check if the time exceeds the trimmed duration of the currently playing media
+                let frame = if currently_playing.play_length_ms() <= current_millis {
                     // Remove timer
-                    timers_lock.remove(station_id);
-                } else if current_time == 0 {
-                    // Convert currently playing media to json
-                    as_json = match serde_json::to_string(currently_playing) {
-                        Ok(v) => v,
-                        Err(_) => {
-                            eprintln!("Could not serialize media");
-                            continue;
-                        },
-                    }
-                }
+                    let started_at = timers_lock.remove(station_id).map(|t| t.started_at());
+
+                    // Move on to the next media, the change starts playback of it
+                    advance(*station_id, currently_playing, &mut redis_con, started_at, false).await;
+                    continue;
+                } else if current_millis < 1000 {
+                    // Announce the currently playing media
+                    PlaybackFrame::new(*station_id, Some(currently_playing), current_millis).to_frame("playing")
+                } else {
+                    // Otherwise send the station time
+                    PlaybackFrame::new(*station_id, Some(currently_playing), current_millis).without_media().to_frame("tick")
+                };
 
                 for (_, client) in clients_lock.iter()
                     .filter(|&(k, _)| joined_clients.contains(k)) {
                     if let Some(sender) = &client.sender {
-                        // If media json is empty send the station time
-                        if as_json.is_empty() {
-                            let _ = sender.send(Ok(Message::text(current_time.to_string())));
-                        } else {
-                            // If media json is present send the currently playing media
-                            let _ = sender.send(Ok(Message::text("playing=".to_string() + &as_json.clone())));
-                        }
+                        let _ = sender.send(Ok(Message::text(frame.clone())));
                     }
                 }
             }
         }
+
+        // Release the locks before removing members of deleted stations
+        drop(clients_lock);
+        drop(timers_lock);
+        drop(stations_lock);
+        for station_id in deleted {
+            self.station_deleted(station_id, clients, &mut redis_con).await;
+        }
+
+        // Places freed by crashed instances are not announced, so check the waiting rooms now and then
+        let waiting: Vec<Uuid> = self.waiting.read().await.iter()
+            .filter(|(_, waiting)| !waiting.is_empty())
+            .map(|(station_id, _)| *station_id)
+            .collect();
+        for station_id in waiting {
+            self.admit_waiting(station_id, clients, redis_client.clone()).await;
+        }
+    }
+
+    // Add a client to a station and tell them what is playing
+    async fn complete_join(&self, station: &Station, id: &str, listener: &Listener, clients: &Clients, redis_connection: &mut Connection) {
+        let station_id = station.id;
+
+        // Bring the other local listeners up to the queue the new listener is about to get
+        self.refresh_queue(station, clients).await;
+
+        // Add user to station
+        let newly_joined = self.join_station(station_id, id).await;
+
+        // Tell every listener about the new listener
+        if newly_joined {
+            if let Err(e) = presence::joined(redis_connection, station_id, id, listener).await {
+                eprintln!("could not update presence: {}", e);
+            }
+        }
+
+        // Tell the client what is playing and how far into it the station is
+        let currently_playing = station.media_queue.first();
+        let elapsed_ms = match currently_playing {
+            Some(_) => self.elapsed_ms(station).await,
+            None => 0,
+        };
+        let joined = PlaybackFrame::new(station_id, currently_playing, elapsed_ms).to_frame("joined");
+        ws::send_to(clients, id, Message::text(joined)).await;
+
+        // Send what is coming up next
+        queue::send_snapshot(clients, id, station_id, &station.media_queue).await;
+    }
+
+    // Remember the queue of a freshly loaded station so later changes can be sent as differences
+    // Local listeners told about an older queue are sent what changed, so every listener starts from the same queue
+    async fn refresh_queue(&self, station: &Station, clients: &Clients) {
+        let previous = self.queues.write().await.insert(station.id, station.media_queue.clone());
+        let previous = match previous {
+            Some(v) => v,
+            None => return,
+        };
+
+        if let Some(frame) = queue::changed_frame(station.id, &previous, &station.media_queue) {
+            self.broadcast(station.id, Message::text(frame), clients).await;
+        }
+
+        // Time media which started while the old queue was cached from when it actually started
+        if previous.first() != station.media_queue.first() {
+            let mut timers_lock = self.timers.write().await;
+            match station.started_at() {
+                Some(started_at) => {
+                    timers_lock.insert(station.id, Timer::since(started_at));
+                }
+                None => {
+                    timers_lock.remove(&station.id);
+                }
+            }
+        }
+    }
+
+    // Drop the cached queues of stations which no local client is listening to anymore
+    // A cached queue nobody is told about would go stale, the next listener starts from a fresh one
+    async fn forget_unlistened(&self, station_ids: &[Uuid]) {
+        let stations_lock = self.stations.read().await;
+        let mut queues_lock = self.queues.write().await;
+
+        for station_id in station_ids {
+            if stations_lock.get(station_id).is_none_or(|members| members.is_empty()) {
+                queues_lock.remove(station_id);
+            }
+        }
     }
 
-    // Add user to stations
-    pub async fn join_station(&self, station_id: Uuid, client_id: &str) {
+    // Take a place in a station for a new listener
+    // Returns false if they have to wait, either because the station is full or others are waiting already
+    async fn claim_place(&self, redis_connection: &mut Connection, station: &Station, id: &str, listener: &Listener) -> redis_direct::Result<bool> {
+        let max_listeners = station.max_listeners.unwrap_or(self.default_max_listeners);
+        if max_listeners == 0 {
+            return Ok(true);
+        }
+
+        if waiting::len(redis_connection, station.id).await? > 0 {
+            return Ok(false);
+        }
+
+        waiting::claim(redis_connection, station.id, id, listener, max_listeners).await
+    }
+
+    // Put a client in the waiting room of a station
+    async fn wait(&self, station_id: Uuid, id: &str, clients: &Clients, redis_connection: &mut Connection) {
+        {
+            let mut waiting_lock = self.waiting.write().await;
+            let waiting = waiting_lock.entry(station_id).or_default();
+            if !waiting.iter().any(|c| c == id) {
+                waiting.push(id.to_string());
+            }
+        }
+
+        let result = match waiting::enter(redis_connection, station_id, id).await {
+            // Let the instance of whoever is first check if they can be let in
+            Ok(_) => fanout::waiting_room_changed(redis_connection, station_id).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!("could not enter waiting room: {}", e);
+            return;
+        }
+
+        if let Ok(Some(position)) = waiting::position(redis_connection, station_id, id).await {
+            ws::send_json(clients, id, "waiting", &WaitingPosition { station_id, position }).await;
+        }
+    }
+
+    // Remove a client from every waiting room apart from the one kept, returns the stations they were waiting for
+    async fn stop_waiting(&self, client_id: &str, keep: Option<Uuid>) -> Vec<Uuid> {
+        let mut left_waiting = Vec::new();
+
+        for (station_id, waiting) in self.waiting.write().await.iter_mut() {
+            if Some(*station_id) == keep {
+                continue;
+            }
+
+            if let Some(index) = waiting.iter().position(|c| c == client_id) {
+                waiting.remove(index);
+                left_waiting.push(*station_id);
+            }
+        }
+
+        left_waiting
+    }
+
+    // Let local clients into a station while it has room and they are first in line
+    // Clients still waiting are told their new position
+    pub async fn admit_waiting(&self, station_id: Uuid, clients: &Clients, redis_client: redis::Client) {
+        if self.waiting.read().await.get(&station_id).is_none_or(|w| w.is_empty()) {
+            return;
+        }
+
+        let mut redis_con: Connection = match get_con(redis_client).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis");
+                return;
+            },
+        };
+        let station = match from_redis(station_id, &mut redis_con).await {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Could not load station: {}", e);
+                return;
+            }
+        };
+        let max_listeners = station.max_listeners.unwrap_or(self.default_max_listeners);
+
+        // Clients of crashed instances would hold up everyone behind them forever
+        let pruned = match waiting::prune(&mut redis_con, station_id).await {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("could not prune waiting room: {}", e);
+                false
+            }
+        };
+
+        let mut admitted = 0;
+        loop {
+            // Only the instance of the client first in line lets them in
+            let head = match waiting::head(&mut redis_con, station_id).await {
+                Ok(Some(v)) if self.is_waiting(station_id, &v).await => v,
+                _ => break,
+            };
+            let client = match clients.read().await.get(&head) {
+                Some(v) => v.clone(),
+                None => break,
+            };
+
+            // Claiming the place takes the client out of the sorted set in the same step
+            match waiting::claim(&mut redis_con, station_id, &head, &client.listener(), max_listeners).await {
+                Ok(true) => {}
+                _ => break,
+            }
+
+            self.stop_waiting_for(station_id, &head).await;
+            self.complete_join(&station, &head, &client.listener(), clients, &mut redis_con).await;
+            admitted += 1;
+        }
+
+        // Everyone behind moved up, let the other instances update their clients too
+        if admitted > 0 || pruned {
+            if let Err(e) = fanout::waiting_room_changed(&mut redis_con, station_id).await {
+                eprintln!("could not update waiting room: {}", e);
+            }
+        }
+
+        let waiting: Vec<String> = self.waiting.read().await.get(&station_id).cloned().unwrap_or_default();
+        for client_id in waiting {
+            if let Ok(Some(position)) = waiting::position(&mut redis_con, station_id, &client_id).await {
+                ws::send_json(clients, &client_id, "waiting", &WaitingPosition { station_id, position }).await;
+            }
+        }
+    }
+
+    // Get every station a client is waiting to get into
+    pub async fn waiting_for(&self, client_id: &str) -> Vec<Uuid> {
+        self.waiting.read().await.iter()
+            .filter(|(_, waiting)| waiting.iter().any(|c| c == client_id))
+            .map(|(station_id, _)| *station_id)
+            .collect()
+    }
+
+    // Check if a local client is waiting to get into a station
+    async fn is_waiting(&self, station_id: Uuid, client_id: &str) -> bool {
+        self.waiting.read().await.get(&station_id).is_some_and(|w| w.iter().any(|c| c == client_id))
+    }
+
+    // Remove a local client from the waiting room of one station
+    async fn stop_waiting_for(&self, station_id: Uuid, client_id: &str) {
+        if let Some(waiting) = self.waiting.write().await.get_mut(&station_id) {
+            waiting.retain(|c| c != client_id);
+        }
+    }
+
+    // Add user to stations, returns false if the user already joined
+    pub async fn join_station(&self, station_id: Uuid, client_id: &str) -> bool {
         // Get write lock on stations
         let mut stations_lock = self.stations.write().await;
 
         // Get mutable user list
-        let mut joined_users = match stations_lock.get(&station_id) {
-            Some(v) => v.clone(),
-            None => Vec::new(),
-        };
+        let joined_users = stations_lock.entry(station_id).or_default();
+        if joined_users.iter().any(|c| c == client_id) {
+            return false;
+        }
 
-        // Add station to user to list
+        // Add user to the station
         joined_users.push(client_id.to_string());
-        // Add user list to station
-        stations_lock.remove(&station_id);
-        stations_lock.insert(station_id, joined_users);
+
+        true
+    }
+
+    // Remove user from every station apart from the one kept, returns the stations they left
+    async fn leave_all(&self, client_id: &str, keep: Option<Uuid>) -> Vec<Uuid> {
+        let mut left_stations = Vec::new();
+
+        for (station_id, joined_users) in self.stations.write().await.iter_mut() {
+            if Some(*station_id) == keep {
+                continue;
+            }
+
+            if let Some(index) = joined_users.iter().position(|c| c == client_id) {
+                joined_users.remove(index);
+                left_stations.push(*station_id);
+            }
+        }
+
+        self.forget_unlistened(&left_stations).await;
+
+        left_stations
     }
-}
\ No newline at end of file
+
+    // Remove a client from every station and waiting room apart from the one kept
+    // Returns the stations the client left
+    async fn leave_except(&self, client_id: &str, keep: Option<Uuid>, listener: &Listener, redis_connection: &mut Connection) -> Vec<Uuid> {
+        let left_stations = self.leave_all(client_id, keep).await;
+        let left_waiting = self.stop_waiting(client_id, keep).await;
+
+        // Tell the remaining listeners
+        for station_id in &left_stations {
+            if let Err(e) = presence::left(redis_connection, *station_id, client_id, listener).await {
+                eprintln!("could not update presence: {}", e);
+            }
+        }
+
+        // Move everyone behind the client up
+        for station_id in left_waiting {
+            let result = match waiting::leave(redis_connection, station_id, client_id).await {
+                Ok(_) => fanout::waiting_room_changed(redis_connection, station_id).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                eprintln!("could not update waiting room: {}", e);
+            }
+        }
+
+        left_stations
+    }
+
+    // Get every station a user joined
+    pub async fn joined_stations(&self, client_id: &str) -> Vec<Uuid> {
+        self.stations.read().await.iter()
+            .filter(|(_, joined_users)| joined_users.iter().any(|c| c == client_id))
+            .map(|(station_id, _)| *station_id)
+            .collect()
+    }
+
+    // Remove every local connection of a user from a station
+    pub async fn kick(&self, station_id: Uuid, user_id: usize, clients: &Clients, redis_client: redis::Client) {
+        // Find the connections of the user in the station
+        let kicked: Vec<(String, Listener)> = {
+            let mut stations_lock = self.stations.write().await;
+            let joined_users = match stations_lock.get_mut(&station_id) {
+                Some(v) => v,
+                None => return,
+            };
+
+            let clients_lock = clients.read().await;
+            let kicked: Vec<(String, Listener)> = joined_users.iter()
+                .filter_map(|c| clients_lock.get(c).map(|client| (c.clone(), client)))
+                .filter(|(_, client)| client.user_id == user_id)
+                .map(|(c, client)| (c, client.listener()))
+                .collect();
+            joined_users.retain(|c| !kicked.iter().any(|(k, _)| k == c));
+
+            kicked
+        };
+        if kicked.is_empty() {
+            return;
+        }
+        self.forget_unlistened(&[station_id]).await;
+
+        let mut redis_con: Connection = match get_con(redis_client).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis");
+                return;
+            },
+        };
+
+        for (client_id, listener) in kicked {
+            // Tell the user they were removed
+            ws::send_json(clients, &client_id, "kicked", &StationRef { station_id }).await;
+
+            if let Err(e) = presence::left(&mut redis_con, station_id, &client_id, &listener).await {
+                eprintln!("could not update presence: {}", e);
+            }
+        }
+    }
+
+    // Find which station a request is for
+    // The station id may be left out when the client is only in one station
+    pub async fn resolve_station(&self, client_id: &str, station_id: Option<Uuid>) -> Option<Uuid> {
+        let joined = self.joined_stations(client_id).await;
+
+        match station_id {
+            Some(v) if joined.contains(&v) => Some(v),
+            Some(_) => None,
+            None if joined.len() == 1 => joined.first().copied(),
+            None => None,
+        }
+    }
+
+    // Get how long the playing media of a station has been playing
+    // Starts the timer if this instance was not timing the station yet, from the start time stored with the station
+    pub async fn elapsed_ms(&self, station: &Station) -> u64 {
+        let mut timers_lock = self.timers.write().await;
+
+        match timers_lock.get(&station.id) {
+            Some(v) => v.get_millis().unwrap_or(0),
+            None => {
+                let timer = station.started_at().map(Timer::since).unwrap_or_else(Timer::new);
+                let elapsed = timer.get_millis().unwrap_or(0);
+                timers_lock.insert(station.id, timer);
+
+                elapsed
+            }
+        }
+    }
+
+    // Get the unix time in milliseconds the playing media of a station started
+    pub async fn started_at(&self, station_id: Uuid) -> Option<u64> {
+        self.timers.read().await.get(&station_id).map(|t| t.started_at())
+    }
+
+    // Check if any local client is in a station or waiting to get in
+    pub async fn has_members(&self, station_id: Uuid) -> bool {
+        self.stations.read().await.get(&station_id).is_some_and(|c| !c.is_empty())
+            || self.waiting.read().await.get(&station_id).is_some_and(|w| !w.is_empty())
+    }
+
+    // Tell local members how the queue of a station changed after it was written
+    // Restarts playback when the media at the front of the queue changed
+    pub async fn queue_updated(&self, station_id: Uuid, queue: &[Media], clients: &Clients) {
+        let previous = self.queues.write().await.insert(station_id, queue.to_vec());
+
+        let previous = match previous {
+            Some(v) => v,
+            None => {
+                // Nothing to compare against, send the whole queue
+                let frame = queue::snapshot_frame(station_id, queue);
+                self.broadcast(station_id, Message::text(frame), clients).await;
+                return;
+            }
+        };
+
+        if let Some(frame) = queue::changed_frame(station_id, &previous, queue) {
+            self.broadcast(station_id, Message::text(frame), clients).await;
+        }
+
+        if previous.first() == queue.first() {
+            return;
+        }
+
+        // Time the new media from the moment it is announced
+        match queue.first() {
+            Some(playing) => {
+                self.timers.write().await.insert(station_id, Timer::new());
+                let frame = PlaybackFrame::new(station_id, Some(playing), 0).to_frame("playing");
+                self.broadcast(station_id, Message::text(frame), clients).await;
+            }
+            None => {
+                self.timers.write().await.remove(&station_id);
+            }
+        }
+    }
+
+    // Tell local members a station was deleted and remove them from it
+    pub async fn station_deleted(&self, station_id: Uuid, clients: &Clients, redis_connection: &mut Connection) {
+        let mut members = self.stations.write().await.remove(&station_id).unwrap_or_default();
+        members.extend(self.waiting.write().await.remove(&station_id).unwrap_or_default());
+        if members.is_empty() {
+            return;
+        }
+        self.timers.write().await.remove(&station_id);
+        self.queues.write().await.remove(&station_id);
+
+        for client_id in &members {
+            ws::send_json(clients, client_id, "station_deleted", &StationRef { station_id }).await;
+        }
+
+        // Nobody is listening to or waiting for a station which does not exist
+        if let Err(e) = presence::clear(redis_connection, station_id).await {
+            eprintln!("could not clear presence: {}", e);
+        }
+        if let Err(e) = waiting::clear(redis_connection, station_id).await {
+            eprintln!("could not clear waiting room: {}", e);
+        }
+    }
+
+    // Send a message to every local client in a station
+    pub async fn broadcast(&self, station_id: Uuid, message: Message, clients: &Clients) {
+        let stations_lock = self.stations.read().await;
+        let joined_users = match stations_lock.get(&station_id) {
+            Some(v) => v,
+            None => return,
+        };
+
+        let clients_lock = clients.read().await;
+        for client_id in joined_users {
+            if let Some(sender) = clients_lock.get(client_id).and_then(|c| c.sender.as_ref()) {
+                let _ = sender.send(Ok(message.clone()));
+            }
+        }
+    }
+}
+
+// Stations a connection is in or waiting for
+#[derive(Debug, Serialize)]
+struct Memberships {
+    #[serde(rename = "mode")]
+    mode: StationMode,
+    #[serde(rename = "stations")]
+    stations: Vec<Uuid>,
+    #[serde(rename = "waiting")]
+    waiting: Vec<WaitingPosition>,
+}
+
+// Receiver for clients listing the stations they are in
+pub struct MembershipsReceiver {
+    pub stations: Arc<StationManager>,
+}
+#[async_trait]
+impl Receiver for MembershipsReceiver {
+    async fn receive_msg(&self, id: &str, _msg: &str, clients: &Clients, redis_client: redis::Client) {
+        let mode = match clients.read().await.get(id) {
+            Some(v) => v.station_mode,
+            None => return,
+        };
+
+        // Look up the position in each waiting room
+        let mut waiting = Vec::new();
+        let waiting_for = self.stations.waiting_for(id).await;
+        if !waiting_for.is_empty() {
+            let mut redis_con: Connection = match get_con(redis_client).await {
+                Ok(v) => v,
+                Err(_) => {
+                    eprintln!("could not connect to redis");
+                    return;
+                },
+            };
+
+            for station_id in waiting_for {
+                if let Ok(Some(position)) = waiting::position(&mut redis_con, station_id, id).await {
+                    waiting.push(WaitingPosition { station_id, position });
+                }
+            }
+        }
+
+        let memberships = Memberships {
+            mode,
+            stations: self.stations.joined_stations(id).await,
+            waiting,
+        };
+        ws::send_json(clients, id, "memberships", &memberships).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Media of the given length with optional trims, in milliseconds
+    fn media(duration_ms: u64, start_offset: Option<u64>, end_trim: Option<u64>) -> Media {
+        serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "url": "https://example.com/test.mp3",
+            "durationMs": duration_ms,
+            "streamingService": "HTTP",
+            "startOffset": start_offset,
+            "endTrim": end_trim,
+        })).expect("test media is valid")
+    }
+
+    #[test]
+    fn check_trim() {
+        let cases = [
+            // (duration, start offset, end trim, accepted)
+            (60_000, None, None, true),
+            (60_000, Some(10_000), Some(10_000), true),
+            (60_000, Some(59_999), None, true),
+            (60_000, Some(60_000), None, false),
+            (60_000, None, Some(60_000), false),
+            (60_000, Some(30_000), Some(30_000), false),
+            (60_000, Some(u64::MAX), Some(1), false),
+            (0, None, None, false),
+        ];
+
+        for (duration, start_offset, end_trim, accepted) in cases {
+            let media = media(duration, start_offset, end_trim);
+            assert_eq!(media.check_trim().is_ok(), accepted, "{:?} {:?} {:?}", duration, start_offset, end_trim);
+        }
+    }
+
+    #[test]
+    fn position_does_not_overflow() {
+        let media = media(60_000, Some(u64::MAX), None);
+        let frame = PlaybackFrame::new(Uuid::new_v4(), Some(&media), 1000);
+
+        assert_eq!(frame.position_ms, Some(u64::MAX));
+    }
+
+    #[test]
+    fn role_of_id() {
+        let mut station = Station::new("owner", 1, "test");
+        station.set_role(2, Role::Dj);
+        station.set_role(3, Role::Banned);
+
+        let cases = [
+            // (user id, role)
+            (1, Role::Owner),
+            (2, Role::Dj),
+            (3, Role::Banned),
+            (4, Role::Listener),
+        ];
+
+        for (user_id, role) in cases {
+            assert_eq!(station.role_of_id(user_id), role, "{}", user_id);
+        }
+    }
+
+    #[test]
+    fn outranks() {
+        let cases = [
+            // (moderator, target, allowed)
+            (Role::Owner, Role::Dj, true),
+            (Role::Owner, Role::Owner, false),
+            (Role::Dj, Role::Listener, true),
+            (Role::Dj, Role::Banned, true),
+            (Role::Dj, Role::Dj, false),
+            (Role::Dj, Role::Owner, false),
+        ];
+
+        for (moderator, target, allowed) in cases {
+            assert_eq!(moderator.outranks(target), allowed, "{:?} {:?}", moderator, target);
+        }
+    }
+
+    // Station json as written before schema versions existed
+    fn unversioned() -> Value {
+        serde_json::json!({
+            "id": "6f1c2b1e-8f0a-4c3e-9b59-0a5f7f3d2c11",
+            "ownerUsername": "owner",
+            "name": "test",
+            "mediaQueue": [
+                {"name": "first", "url": "https://example.com/1.mp3", "duration": 90, "streamingService": "HTTP"},
+                {"name": "second", "url": "https://example.com/2.mp3", "duration": 30, "durationMs": 30_500, "streamingService": "HTTP"},
+            ],
+        })
+    }
+
+    #[test]
+    fn migrate_unversioned() {
+        let value = migrate(unversioned());
+
+        assert_eq!(value["schemaVersion"], 2);
+        assert_eq!(value["mediaQueue"][0]["durationMs"], 90_000);
+        // Precise durations which were already there are kept
+        assert_eq!(value["mediaQueue"][1]["durationMs"], 30_500);
+
+        let station: Station = serde_json::from_value(value).expect("migrated station is valid");
+        assert_eq!(station.schema_version, SCHEMA_VERSION);
+        assert_eq!(station.media_queue[0].duration_ms(), 90_000);
+    }
+
+    #[test]
+    fn migrate_version_1() {
+        let mut value = unversioned();
+        value["schemaVersion"] = Value::from(1);
+        value["mediaQueue"][0]["duration"] = Value::from(-5);
+        let value = migrate(value);
+
+        assert_eq!(value["schemaVersion"], 2);
+        // Broken negative durations become 0 instead of wrapping
+        assert_eq!(value["mediaQueue"][0]["durationMs"], 0);
+        assert_eq!(value["mediaQueue"][1]["durationMs"], 30_500);
+    }
+
+    #[test]
+    fn migrate_leaves_newer_versions() {
+        let mut value = unversioned();
+        value["schemaVersion"] = Value::from(SCHEMA_VERSION + 1);
+
+        assert_eq!(migrate(value.clone()), value);
+    }
+
+    #[test]
+    fn unknown_keys_survive_round_trip() {
+        let mut value = unversioned();
+        value["schemaVersion"] = Value::from(SCHEMA_VERSION + 1);
+        value["lighting"] = serde_json::json!({"color": "red"});
+        value["mediaQueue"][0]["lyricsUrl"] = Value::from("https://example.com/1.txt");
+
+        let station: Station = serde_json::from_value(migrate(value)).expect("station is valid");
+        let written: Value = serde_json::from_str(&to_json(&station).expect("station serializes")).unwrap();
+
+        assert_eq!(written["schemaVersion"], SCHEMA_VERSION + 1);
+        assert_eq!(written["lighting"], serde_json::json!({"color": "red"}));
+        assert_eq!(written["mediaQueue"][0]["lyricsUrl"], "https://example.com/1.txt");
+    }
+
+    #[test]
+    fn streaming_service_round_trip() {
+        let cases = [
+            // (json name, service)
+            ("SPOTIFY", StreamingService::Spotify),
+            ("YOUTUBE", StreamingService::YouTube),
+            ("APPLE_MUSIC", StreamingService::AppleMusic),
+            ("HTTP", StreamingService::Http),
+            // Services this server does not know about are kept as they were written
+            ("VIMEO", StreamingService::Other("VIMEO".to_string())),
+            ("youtube", StreamingService::Other("youtube".to_string())),
+            ("", StreamingService::Other(String::new())),
+        ];
+
+        for (name, service) in cases {
+            let json = Value::from(name);
+            let read: StreamingService = serde_json::from_value(json.clone()).expect("every string is a service");
+            assert_eq!(read, service, "{}", name);
+            assert_eq!(serde_json::to_value(&read).unwrap(), json, "{}", name);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::time::{SystemTime, SystemTimeError};
+use std::time::{Duration, SystemTime, SystemTimeError, UNIX_EPOCH};
 
 // Add structure for timer
 pub struct Timer {
@@ -8,17 +8,41 @@ pub struct Timer {
 // Logic for timer
 impl Timer {
     pub fn new() -> Timer {
-        return Timer {
+        Timer {
             // Start timer at the current system time
             start_time: SystemTime::now()
         }
     }
 
-    pub fn get_time(&self) -> Result<u64, SystemTimeError> {
-        // Determine difference from now to when the timer was created
-        return match SystemTime::now().duration_since(self.start_time) {
-            Ok(v) => Ok(v.as_secs()),
+    // Create a timer which started at a unix time in milliseconds, possibly on another instance
+    pub fn since(started_at: u64) -> Timer {
+        Timer {
+            start_time: UNIX_EPOCH + Duration::from_millis(started_at)
+        }
+    }
+
+    pub fn get_millis(&self) -> Result<u64, SystemTimeError> {
+        // Determine difference in milliseconds from now to when the timer was created
+        match SystemTime::now().duration_since(self.start_time) {
+            Ok(v) => Ok(v.as_millis() as u64),
             Err(e) => Err(e),
         }
     }
-}
\ No newline at end of file
+
+    // Get the unix time in milliseconds the timer was started at
+    pub fn started_at(&self) -> u64 {
+        match self.start_time.duration_since(UNIX_EPOCH) {
+            Ok(v) => v.as_millis() as u64,
+            Err(_) => 0,
+        }
+    }
+}
+
+// Get the current wall clock time as milliseconds since the unix epoch
+pub fn unix_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(v) => v.as_millis() as u64,
+        // Clock is set before 1970, report the epoch itself
+        Err(_) => 0,
+    }
+}
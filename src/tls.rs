@@ -0,0 +1,198 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use rustls::ServerConfig;
+use rustls::crypto::ring;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::time;
+use tokio_rustls::TlsAcceptor;
+use warp::Filter;
+use warp::hyper::{Body, Request, Response};
+use warp::hyper::server::conn::Http;
+use warp::hyper::service::{service_fn, Service};
+
+// Seconds a client gets to finish the TLS handshake
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("could not read {0}: {1}")]
+    Io(PathBuf, io::Error),
+    #[error("no certificates in {0}")]
+    NoCertificates(PathBuf),
+    #[error("no private key in {0}")]
+    NoKey(PathBuf),
+    #[error("unusable private key: {0}")]
+    Key(rustls::Error),
+}
+
+// Address of the client of a TLS connection, warp only knows the address of plain connections
+#[derive(Debug, Clone, Copy)]
+struct RemoteAddr(SocketAddr);
+
+// Filter extracting the address of the client, with or without TLS
+pub fn remote() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<RemoteAddr>())
+        .map(|addr: Option<SocketAddr>, tls: Option<RemoteAddr>| addr.or(tls.map(|t| t.0)))
+}
+
+// Load a certificate chain and its private key from PEM files
+fn load(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, TlsError> {
+    let open = |path: &Path| File::open(path).map(BufReader::new).map_err(|e| TlsError::Io(path.to_path_buf(), e));
+
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Io(cert_path.to_path_buf(), e))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(cert_path.to_path_buf()));
+    }
+
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)
+        .map_err(|e| TlsError::Io(key_path.to_path_buf(), e))?
+        .ok_or_else(|| TlsError::NoKey(key_path.to_path_buf()))?;
+
+    // Also checks the key belongs to the certificate
+    CertifiedKey::from_der(certs, key, &ring::default_provider()).map_err(TlsError::Key)
+}
+
+// Get the time both files were last changed
+fn modified(cert_path: &Path, key_path: &Path) -> Option<(SystemTime, SystemTime)> {
+    let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+    Some((modified(cert_path)?, modified(key_path)?))
+}
+
+// Certificate handed to new connections, replaced when the files on disk change
+// Connections which already finished their handshake keep the certificate they got
+pub struct ReloadingCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    modified: RwLock<Option<(SystemTime, SystemTime)>>,
+}
+
+impl fmt::Debug for ReloadingCert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadingCert")
+            .field("cert_path", &self.cert_path)
+            .field("key_path", &self.key_path)
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|c| c.clone())
+    }
+}
+
+impl ReloadingCert {
+    // Load the certificate for the first time
+    pub fn new(cert_path: PathBuf, key_path: PathBuf) -> Result<ReloadingCert, TlsError> {
+        let modified = modified(&cert_path, &key_path);
+        let current = load(&cert_path, &key_path)?;
+
+        Ok(ReloadingCert {
+            cert_path,
+            key_path,
+            current: RwLock::new(Arc::new(current)),
+            modified: RwLock::new(modified),
+        })
+    }
+
+    // Load the files again if they changed since the last load
+    // A broken certificate is logged and the previous one stays in use, it is tried again on the next check
+    // since the files may have been caught halfway through a rotation
+    pub fn reload(&self) {
+        let modified = modified(&self.cert_path, &self.key_path);
+        if modified.is_none() || self.modified.read().is_ok_and(|m| *m == modified) {
+            return;
+        }
+
+        let cert = match load(&self.cert_path, &self.key_path) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("could not reload tls certificate: {}", e);
+                return;
+            }
+        };
+
+        if let Ok(mut current) = self.current.write() {
+            *current = Arc::new(cert);
+        }
+        if let Ok(mut m) = self.modified.write() {
+            *m = modified;
+        }
+        println!("reloaded tls certificate from {}", self.cert_path.display());
+    }
+
+    // Check the files for changes forever
+    pub async fn watch(self: Arc<Self>, interval_secs: u64) {
+        let mut interval = time::interval(Duration::from_secs(interval_secs));
+
+        loop {
+            interval.tick().await;
+            self.reload();
+        }
+    }
+}
+
+// Serve a warp service over TLS, the certificate is picked for each new connection
+pub async fn serve<S>(service: S, addr: SocketAddr, cert: Arc<ReloadingCert>)
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .with_no_client_auth()
+        .with_cert_resolver(cert);
+    // Websockets are upgraded from HTTP/1.1 requests
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind(addr).await.expect("can bind listen address");
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("could not accept connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+
+        tokio::spawn(async move {
+            // Drop clients which never finish the handshake
+            let stream = match time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS), acceptor.accept(stream)).await {
+                Ok(Ok(v)) => v,
+                Ok(Err(e)) => {
+                    eprintln!("tls handshake with {} failed: {}", peer, e);
+                    return;
+                }
+                Err(_) => return,
+            };
+
+            // Tell the routes where the request came from
+            let service = service_fn(move |mut request: Request<Body>| {
+                request.extensions_mut().insert(RemoteAddr(peer));
+                service.clone().call(request)
+            });
+
+            if let Err(e) = Http::new().http1_only(true).serve_connection(stream, service).with_upgrades().await {
+                eprintln!("error serving {}: {}", peer, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,141 @@
+// Checks for input sent by clients
+
+// Longest receiver id accepted in a frame
+pub const MAX_RECEIVER_ID_LEN: usize = 32;
+// Longest join code accepted, generated codes are shorter
+pub const MAX_JOIN_CODE_LEN: usize = 16;
+// Longest topic name accepted
+pub const MAX_TOPIC_LEN: usize = 64;
+// Most topics a client may listen to at once
+pub const MAX_TOPICS: usize = 32;
+
+// A text frame sent by a client
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame<'a> {
+    // Keep alive, nothing to answer
+    Ping,
+    // Payload for the receiver registered under an id
+    Request { receiver_id: &'a str, payload: &'a str },
+    // Frame which could not be read, with the reason sent back to the client
+    Invalid(&'static str),
+}
+
+// Split a text frame into its receiver id and payload, formatted as <id>=<payload>
+pub fn parse_frame(message: &str) -> Frame<'_> {
+    if message == "ping" || message == "ping\n" {
+        return Frame::Ping;
+    }
+
+    let (receiver_id, payload) = match message.split_once('=') {
+        Some(v) => v,
+        None => return Frame::Invalid("expected <id>=<value>"),
+    };
+
+    if !valid_receiver_id(receiver_id) {
+        return Frame::Invalid("invalid receiver id");
+    }
+
+    Frame::Request { receiver_id, payload }
+}
+
+// Receiver ids are short lowercase names such as join_station
+fn valid_receiver_id(receiver_id: &str) -> bool {
+    !receiver_id.is_empty()
+        && receiver_id.len() <= MAX_RECEIVER_ID_LEN
+        && receiver_id.bytes().all(|b| b.is_ascii_lowercase() || b == b'_')
+}
+
+// Join codes are short and only use letters and digits
+pub fn valid_join_code(code: &str) -> bool {
+    !code.is_empty()
+        && code.len() <= MAX_JOIN_CODE_LEN
+        && code.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+// Topic names use letters, digits and a few separators
+pub fn valid_topic(topic: &str) -> bool {
+    !topic.is_empty()
+        && topic.len() <= MAX_TOPIC_LEN
+        && topic.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'-' | b'.' | b':' | b'/'))
+}
+
+// Check the topics a client wants to listen to, returning the reason they are rejected
+pub fn check_topics(topics: &[String]) -> Result<(), &'static str> {
+    if topics.len() > MAX_TOPICS {
+        return Err("too many topics");
+    }
+
+    if !topics.iter().all(|t| valid_topic(t)) {
+        return Err("topics may only use letters, digits and _-.:/ and must be at most 64 characters");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_frame() {
+        let cases = [
+            // (message, frame)
+            ("ping", Frame::Ping),
+            ("ping\n", Frame::Ping),
+            ("join_station=ABC123", Frame::Request { receiver_id: "join_station", payload: "ABC123" }),
+            ("queue=", Frame::Request { receiver_id: "queue", payload: "" }),
+            // Only the first equals splits the frame
+            ("station_chat={\"text\":\"a=b\"}", Frame::Request { receiver_id: "station_chat", payload: "{\"text\":\"a=b\"}" }),
+            ("", Frame::Invalid("expected <id>=<value>")),
+            ("pong", Frame::Invalid("expected <id>=<value>")),
+            ("=payload", Frame::Invalid("invalid receiver id")),
+            ("Join_station=1", Frame::Invalid("invalid receiver id")),
+            ("join station=1", Frame::Invalid("invalid receiver id")),
+            ("time_sync2=1", Frame::Invalid("invalid receiver id")),
+            ("abcdefghijklmnopqrstuvwxyzabcdefg=1", Frame::Invalid("invalid receiver id")),
+        ];
+
+        for (message, frame) in cases {
+            assert_eq!(super::parse_frame(message), frame, "{}", message);
+        }
+    }
+
+    #[test]
+    fn valid_join_code() {
+        let cases = [
+            // (code, valid)
+            ("ABC123", true),
+            ("abcdefghijklmnop", true),
+            ("abcdefghijklmnopq", false),
+            ("", false),
+            ("ABC-123", false),
+            ("ABC 123", false),
+            ("ÄBC", false),
+        ];
+
+        for (code, valid) in cases {
+            assert_eq!(super::valid_join_code(code), valid, "{}", code);
+        }
+    }
+
+    #[test]
+    fn check_topics() {
+        let long = "a".repeat(MAX_TOPIC_LEN + 1);
+        let cases = [
+            // (topics, accepted)
+            (vec![], true),
+            (vec!["news".to_string(), "station:1/chat".to_string(), "a_b-c.d".to_string()], true),
+            (vec!["a".repeat(MAX_TOPIC_LEN)], true),
+            (vec![long], false),
+            (vec![String::new()], false),
+            (vec!["news feed".to_string()], false),
+            (vec!["news*".to_string()], false),
+            (vec!["t".to_string(); MAX_TOPICS], true),
+            (vec!["t".to_string(); MAX_TOPICS + 1], false),
+        ];
+
+        for (topics, accepted) in cases {
+            assert_eq!(super::check_topics(&topics).is_ok(), accepted, "{:?}", topics);
+        }
+    }
+}
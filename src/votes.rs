@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use async_trait::async_trait;
+use redis::aio::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::from_str;
+use uuid::Uuid;
+use crate::{Clients, fanout, presence, ws};
+use crate::DirectError::RedisCMDError;
+use crate::message_receive::Receiver;
+use crate::redis_direct::{get_con, Result};
+use crate::station::{self, advance, from_redis, Media, StationManager};
+
+// Request to vote to skip the playing media
+#[derive(Deserialize, Debug)]
+pub struct SkipVoteRequest {
+    #[serde(rename = "stationId")]
+    station_id: Option<Uuid>,
+}
+
+// Direction of a vote on queued media
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum Direction {
+    #[serde(rename = "up")]
+    Up,
+    #[serde(rename = "down")]
+    Down,
+    #[serde(rename = "clear")]
+    Clear,
+}
+
+// Request to vote on media waiting in the queue
+#[derive(Deserialize, Debug)]
+pub struct MediaVoteRequest {
+    #[serde(rename = "stationId")]
+    station_id: Option<Uuid>,
+    #[serde(rename = "url")]
+    url: String,
+    #[serde(rename = "direction")]
+    direction: Direction,
+}
+
+// Current vote counts of a station, sent to every listener when they change
+#[derive(Serialize, Debug)]
+struct Tally {
+    #[serde(rename = "stationId")]
+    station_id: Uuid,
+    #[serde(rename = "skipVotes")]
+    skip_votes: usize,
+    #[serde(rename = "skipNeeded")]
+    skip_needed: usize,
+    // Score of each queued media by url
+    #[serde(rename = "scores")]
+    scores: HashMap<String, i64>,
+}
+
+// Construct the redis key of the users voting to skip a media while it plays
+// Keyed by the media so a vote cast just before the station moves on does not count against the next one
+fn skip_key(station_id: Uuid, url: &str) -> String {
+    format!("station-skip:{}:{}", station_id, url)
+}
+
+// Construct the redis key of the scores of queued media
+fn scores_key(station_id: Uuid) -> String {
+    "station-votes:".to_owned() + &station_id.to_string()
+}
+
+// Construct the redis key of the votes users cast on a media
+fn voters_key(station_id: Uuid, url: &str) -> String {
+    format!("station-voters:{}:{}", station_id, url)
+}
+
+// Times a reorder is tried again when the station keeps being written by someone else
+const MAX_REORDER_ATTEMPTS: usize = 5;
+
+// Replaces the earlier vote of a user on a media and updates its score in one step
+// Two connections of the same user voting at once would otherwise both see the old vote and count twice
+// KEYS[1] voters of the media, KEYS[2] scores of the station
+// ARGV: user id, vote of 1, -1 or 0 to take it back, url of the media
+const VOTE_SCRIPT: &str = r#"
+local previous = tonumber(redis.call('HGET', KEYS[1], ARGV[1]) or 0)
+local value = tonumber(ARGV[2])
+if value == 0 then
+    redis.call('HDEL', KEYS[1], ARGV[1])
+else
+    redis.call('HSET', KEYS[1], ARGV[1], value)
+end
+return redis.call('HINCRBY', KEYS[2], ARGV[3], value - previous)
+"#;
+
+// Forget the votes for media which finished playing
+pub async fn clear(con: &mut Connection, station_id: Uuid, finished: &Media) {
+    let result = redis::pipe()
+        .cmd("DEL").arg(skip_key(station_id, finished.url())).ignore()
+        .cmd("HDEL").arg(scores_key(station_id)).arg(finished.url()).ignore()
+        .cmd("DEL").arg(voters_key(station_id, finished.url())).ignore()
+        .query_async::<_, ()>(con)
+        .await;
+
+    if let Err(e) = result {
+        eprintln!("could not clear votes: {}", e);
+    }
+}
+
+// Find the keys matching a pattern, used for the keys kept for each media of a station
+async fn keys_matching(con: &mut Connection, pattern: &str) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut cursor: u64 = 0;
+
+    // Scan instead of KEYS so a large database is not blocked
+    loop {
+        let (next, found): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(100)
+            .query_async(con)
+            .await
+            .map_err(RedisCMDError)?;
+        keys.extend(found);
+
+        if next == 0 {
+            return Ok(keys);
+        }
+        cursor = next;
+    }
+}
+
+// Drop the skip votes, scores and voters of a deleted station
+pub async fn forget(con: &mut Connection, station_id: Uuid) {
+    let mut keys = vec![scores_key(station_id)];
+    for pattern in [voters_key(station_id, "*"), skip_key(station_id, "*")] {
+        match keys_matching(con, &pattern).await {
+            Ok(v) => keys.extend(v),
+            Err(e) => eprintln!("could not find votes of station {}: {}", station_id, e),
+        }
+    }
+
+    let result = redis::cmd("DEL")
+        .arg(keys)
+        .query_async::<_, i64>(con)
+        .await;
+
+    if let Err(e) = result {
+        eprintln!("could not forget votes: {}", e);
+    }
+}
+
+// Get the score of every queued media in a station
+async fn scores(con: &mut Connection, station_id: Uuid) -> Result<HashMap<String, i64>> {
+    redis::cmd("HGETALL")
+        .arg(scores_key(station_id))
+        .query_async(con)
+        .await
+        .map_err(|e| RedisCMDError(e).into())
+}
+
+// Amount of skip votes needed for the current amount of listeners
+async fn skip_needed(con: &mut Connection, station_id: Uuid, threshold: f64) -> Result<usize> {
+    let listeners = presence::roster(con, station_id).await?.len();
+
+    Ok(((listeners as f64 * threshold).ceil() as usize).max(1))
+}
+
+// Send the current vote counts to every listener of a station
+async fn broadcast_tally(con: &mut Connection, station_id: Uuid, threshold: f64) -> Result<()> {
+    // Only the skip votes against the media playing now count
+    let skip_votes: usize = match from_redis(station_id, con).await.ok().and_then(|s| s.media_queue().first().cloned()) {
+        Some(playing) => redis::cmd("SCARD")
+            .arg(skip_key(station_id, playing.url()))
+            .query_async(con)
+            .await
+            .map_err(RedisCMDError)?,
+        None => 0,
+    };
+
+    let tally = Tally {
+        station_id,
+        skip_votes,
+        skip_needed: skip_needed(con, station_id, threshold).await?,
+        scores: scores(con, station_id).await?,
+    };
+
+    fanout::send_json(con, station_id, "votes", &tally).await
+}
+
+// Receiver for votes to skip the playing media
+pub struct SkipVoteReceiver {
+    pub stations: Arc<StationManager>,
+    // Fraction of current listeners which must vote to skip
+    pub threshold: f64,
+}
+
+impl SkipVoteReceiver {
+    async fn vote(&self, con: &mut Connection, station_id: Uuid, user_id: usize) -> Result<()> {
+        // Remember what the vote is against, the media may change before enough votes are in
+        let playing = match from_redis(station_id, con).await {
+            Ok(station) => station.media_queue().first().cloned(),
+            Err(e) => {
+                eprintln!("{}", e);
+                None
+            }
+        };
+
+        // Nothing is playing, so there is nothing to skip
+        let playing = match playing {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        // Record the vote against that media, a user can only vote once per media
+        let votes: usize = redis::pipe()
+            .cmd("SADD").arg(skip_key(station_id, playing.url())).arg(user_id).ignore()
+            .cmd("SCARD").arg(skip_key(station_id, playing.url()))
+            .query_async::<_, (usize,)>(con)
+            .await
+            .map_err(RedisCMDError)?
+            .0;
+
+        if votes >= skip_needed(con, station_id, self.threshold).await? {
+            // Advance the station the same way the timer does
+            // Voters passing the threshold at the same time only skip the media they voted against once
+            let started_at = self.stations.started_at(station_id).await;
+            advance(station_id, &playing, con, started_at, true).await;
+        }
+
+        broadcast_tally(con, station_id, self.threshold).await
+    }
+}
+
+#[async_trait]
+impl Receiver for SkipVoteReceiver {
+    async fn receive_msg(&self, id: &str, msg: &str, clients: &Clients, redis_client: redis::Client) {
+        let request: SkipVoteRequest = match from_str(msg) {
+            Ok(v) => v,
+            Err(_) => {
+                ws::send_error(clients, id, "invalid_request", "expected a skip vote").await;
+                return;
+            }
+        };
+
+        // Ensure the client is in the station
+        let station_id = match self.stations.resolve_station(id, request.station_id).await {
+            Some(v) => v,
+            None => {
+                ws::send_error(clients, id, "not_in_station", "join the station before voting").await;
+                return;
+            }
+        };
+
+        let user_id = match clients.read().await.get(id) {
+            Some(v) => v.user_id,
+            None => return,
+        };
+
+        // Establish connection to redis
+        let mut redis_con = match get_con(redis_client).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis");
+                return;
+            }
+        };
+
+        if let Err(e) = self.vote(&mut redis_con, station_id, user_id).await {
+            eprintln!("could not record skip vote: {}", e);
+        }
+    }
+}
+
+// Receiver for up and down votes on queued media
+pub struct MediaVoteReceiver {
+    pub stations: Arc<StationManager>,
+    // Fraction of current listeners which must vote to skip, sent along with the tally
+    pub threshold: f64,
+}
+
+impl MediaVoteReceiver {
+    async fn vote(&self, con: &mut Connection, station_id: Uuid, user_id: usize, request: &MediaVoteRequest) -> Result<()> {
+        let value = match request.direction {
+            Direction::Up => 1,
+            Direction::Down => -1,
+            Direction::Clear => 0,
+        };
+
+        // Replace any earlier vote of the user on this media
+        redis::Script::new(VOTE_SCRIPT)
+            .key(voters_key(station_id, &request.url))
+            .key(scores_key(station_id))
+            .arg(user_id)
+            .arg(value)
+            .arg(&request.url)
+            .invoke_async::<_, i64>(con)
+            .await
+            .map_err(RedisCMDError)?;
+
+        // Move media with more votes further up the queue
+        // The station is watched so a concurrent write makes the reorder start over with fresh scores
+        for _ in 0..MAX_REORDER_ATTEMPTS {
+            let mut station = match station::watch(station_id, con).await {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    break;
+                }
+            };
+
+            let scores = match scores(con, station_id).await {
+                Ok(v) => v,
+                Err(e) => {
+                    station::unwatch(con).await;
+                    return Err(e);
+                }
+            };
+            if !station.reorder_pending(&scores) {
+                station::unwatch(con).await;
+                break;
+            }
+
+            match station::write_watched(&station, con).await {
+                Ok(true) => break,
+                Ok(false) => continue,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    break;
+                }
+            }
+        }
+
+        broadcast_tally(con, station_id, self.threshold).await
+    }
+}
+
+#[async_trait]
+impl Receiver for MediaVoteReceiver {
+    async fn receive_msg(&self, id: &str, msg: &str, clients: &Clients, redis_client: redis::Client) {
+        let request: MediaVoteRequest = match from_str(msg) {
+            Ok(v) => v,
+            Err(_) => {
+                ws::send_error(clients, id, "invalid_request", "expected a media vote").await;
+                return;
+            }
+        };
+
+        // Ensure the client is in the station
+        let station_id = match self.stations.resolve_station(id, request.station_id).await {
+            Some(v) => v,
+            None => {
+                ws::send_error(clients, id, "not_in_station", "join the station before voting").await;
+                return;
+            }
+        };
+
+        let user_id = match clients.read().await.get(id) {
+            Some(v) => v.user_id,
+            None => return,
+        };
+
+        // Establish connection to redis
+        let mut redis_con = match get_con(redis_client).await {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("could not connect to redis");
+                return;
+            }
+        };
+
+        // Only media waiting in the queue can be voted on
+        match from_redis(station_id, &mut redis_con).await {
+            Ok(station) if station.has_pending(&request.url) => {}
+            _ => {
+                ws::send_error(clients, id, "not_queued", "media is not waiting in the queue").await;
+                return;
+            }
+        }
+
+        if let Err(e) = self.vote(&mut redis_con, station_id, user_id, &request).await {
+            eprintln!("could not record media vote: {}", e);
+        }
+    }
+}
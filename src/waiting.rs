@@ -0,0 +1,187 @@
+use std::collections::HashSet;
+use redis::aio::Connection;
+use serde::Serialize;
+use uuid::Uuid;
+use crate::DirectError::RedisCMDError;
+use crate::instance;
+use crate::presence::{self, Listener};
+use crate::redis_direct::Result;
+use crate::timer::unix_millis;
+
+// Position of a client in the waiting room of a full station
+#[derive(Serialize, Debug)]
+pub struct WaitingPosition {
+    #[serde(rename = "stationId")]
+    pub station_id: Uuid,
+    // 1 is the next client to be let in
+    #[serde(rename = "position")]
+    pub position: usize,
+}
+
+// Construct the redis key of the waiting room of a station
+// The sorted set holds <instance id>:<connection id> scored by the time the connection started waiting
+fn waiting_key(station_id: Uuid) -> String {
+    "station-waiting:".to_owned() + &station_id.to_string()
+}
+
+// Construct the waiting room member of a connection, only the instance holding the connection can let it in
+fn waiting_member(client_id: &str) -> String {
+    format!("{}:{}", instance::id(), client_id)
+}
+
+// Takes a place in a station if the live listeners leave room, all in one step so concurrent joins can not overfill it
+// KEYS[1] presence hash, KEYS[2] waiting room
+// ARGV: own instance id, heartbeat key prefix, presence field, listener json, user id, max listeners, waiting member
+const CLAIM_SCRIPT: &str = r#"
+local entries = redis.call('HGETALL', KEYS[1])
+local users = {}
+local count = 0
+for i = 1, #entries, 2 do
+    local instance = string.match(entries[i], '^([^:]+):')
+    if instance and (instance == ARGV[1] or redis.call('EXISTS', ARGV[2] .. instance) == 1) then
+        local ok, listener = pcall(cjson.decode, entries[i + 1])
+        if ok and type(listener) == 'table' and listener['userId'] then
+            local user = tostring(listener['userId'])
+            if not users[user] then
+                users[user] = true
+                count = count + 1
+            end
+        end
+    end
+end
+
+local max_listeners = tonumber(ARGV[6])
+if max_listeners > 0 and not users[ARGV[5]] and count >= max_listeners then
+    return 0
+end
+
+redis.call('HSET', KEYS[1], ARGV[3], ARGV[4])
+redis.call('ZREM', KEYS[2], ARGV[7])
+return 1
+"#;
+
+// Claim a place in a station for a connection, taking it out of the waiting room
+// A user with a connection in the station already is not counted twice, a max of 0 means no limit
+pub async fn claim(con: &mut Connection, station_id: Uuid, client_id: &str, listener: &Listener, max_listeners: usize) -> Result<bool> {
+    let as_json = serde_json::to_string(listener).unwrap_or_default();
+
+    let claimed: i64 = redis::Script::new(CLAIM_SCRIPT)
+        .key(presence::presence_key(station_id))
+        .key(waiting_key(station_id))
+        .arg(instance::id())
+        .arg(instance::HEARTBEAT_KEY_PREFIX)
+        .arg(presence::presence_field(client_id))
+        .arg(as_json)
+        .arg(listener.user_id)
+        .arg(max_listeners)
+        .arg(waiting_member(client_id))
+        .invoke_async(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    Ok(claimed == 1)
+}
+
+// Count the clients waiting to get into a station
+pub async fn len(con: &mut Connection, station_id: Uuid) -> Result<usize> {
+    redis::cmd("ZCARD")
+        .arg(waiting_key(station_id))
+        .query_async(con)
+        .await
+        .map_err(|e| RedisCMDError(e).into())
+}
+
+// Empty the waiting room of a deleted station
+pub async fn clear(con: &mut Connection, station_id: Uuid) -> Result<()> {
+    redis::cmd("DEL")
+        .arg(waiting_key(station_id))
+        .query_async::<_, i64>(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    Ok(())
+}
+
+// Add a connection to the back of the waiting room, a connection already waiting keeps its place
+pub async fn enter(con: &mut Connection, station_id: Uuid, client_id: &str) -> Result<()> {
+    redis::cmd("ZADD")
+        .arg(waiting_key(station_id))
+        .arg("NX")
+        .arg(unix_millis())
+        .arg(waiting_member(client_id))
+        .query_async::<_, i64>(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    Ok(())
+}
+
+// Take a connection out of the waiting room, returns false if it was not waiting
+pub async fn leave(con: &mut Connection, station_id: Uuid, client_id: &str) -> Result<bool> {
+    let removed: i64 = redis::cmd("ZREM")
+        .arg(waiting_key(station_id))
+        .arg(waiting_member(client_id))
+        .query_async(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    Ok(removed > 0)
+}
+
+// Remove connections of instances which stopped sending heartbeats, nobody would ever let them in
+// Returns true if any were removed
+pub async fn prune(con: &mut Connection, station_id: Uuid) -> Result<bool> {
+    let members: Vec<String> = redis::cmd("ZRANGE")
+        .arg(waiting_key(station_id))
+        .arg(0)
+        .arg(-1)
+        .query_async(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    // Members without an instance were written before instances had heartbeats
+    let instances: HashSet<&str> = members.iter().filter_map(|m| m.split_once(':').map(|(i, _)| i)).collect();
+    let alive = instance::alive(con, &instances).await?;
+    let dead: Vec<&String> = members.iter()
+        .filter(|m| !m.split_once(':').is_some_and(|(i, _)| alive.contains(i)))
+        .collect();
+
+    if dead.is_empty() {
+        return Ok(false);
+    }
+
+    redis::cmd("ZREM")
+        .arg(waiting_key(station_id))
+        .arg(dead)
+        .query_async::<_, i64>(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    Ok(true)
+}
+
+// Get the connection which has waited the longest if this instance holds it
+pub async fn head(con: &mut Connection, station_id: Uuid) -> Result<Option<String>> {
+    let head: Vec<String> = redis::cmd("ZRANGE")
+        .arg(waiting_key(station_id))
+        .arg(0)
+        .arg(0)
+        .query_async(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    Ok(head.into_iter().next()
+        .and_then(|m| m.split_once(':').filter(|(i, _)| *i == instance::id()).map(|(_, c)| c.to_string())))
+}
+
+// Get the position of a connection in the waiting room, starting at 1
+pub async fn position(con: &mut Connection, station_id: Uuid, client_id: &str) -> Result<Option<usize>> {
+    let rank: Option<usize> = redis::cmd("ZRANK")
+        .arg(waiting_key(station_id))
+        .arg(waiting_member(client_id))
+        .query_async(con)
+        .await
+        .map_err(RedisCMDError)?;
+
+    Ok(rank.map(|r| r + 1))
+}
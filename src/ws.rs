@@ -1,22 +1,40 @@
 use async_trait::async_trait;
 use futures::{FutureExt, StreamExt};
-use futures::executor::block_on;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::from_str;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use warp::ws::{Message, WebSocket};
-use crate::{Client, Clients, Receivers};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use crate::{Client, Clients, Deliveries, Limiter, Receivers};
+use crate::ratelimit::Verdict;
+use crate::validate::{self, Frame};
+use crate::history::{self, Replay};
 use crate::message_receive::{Receiver};
+use crate::redis_direct::get_con;
+use crate::timer::unix_millis;
 
 // Structure for adding a new topic
 #[derive(Deserialize, Debug)]
 pub struct TopicsRequest {
     topics: Vec<String>,
+    // Replay the last n messages of each topic
+    last: Option<usize>,
+    // Replay every message of each topic after this stream id
+    since: Option<String>,
+}
+
+impl TopicsRequest {
+    // Ensure the topics could have been published to
+    pub fn check(&self) -> Result<(), &'static str> {
+        validate::check_topics(&self.topics)
+    }
 }
 
 // Handle a new connection to a websocket
-pub async fn client_connection(ws: WebSocket, id: String, clients: Clients, mut client: Client, redis_client: redis::Client, receiver_manager: Receivers) {
+#[allow(clippy::too_many_arguments)]
+pub async fn client_connection(ws: WebSocket, id: String, clients: Clients, mut client: Client, redis_client: redis::Client, receiver_manager: Receivers, deliveries: Deliveries, limiter: Limiter, ip: Option<IpAddr>) {
     // Define senders
     let (client_ws_sender, mut client_ws_rcv) = ws.split();
     let (client_sender, client_rcv) = mpsc::unbounded_channel();
@@ -31,11 +49,15 @@ pub async fn client_connection(ws: WebSocket, id: String, clients: Clients, mut
 
     // Wrap client sender in a optional
     client.sender = Some(client_sender);
+    let user_id = client.user_id;
     // Add client sender to the client struct
     clients.write().await.insert(id.clone(), client);
 
     println!("{} connected", id);
 
+    // Deliver messages the user has not acknowledged yet
+    deliveries.resend_to(user_id, &id, &clients).await;
+
     // Listen for messages from client
     while let Some(result) = client_ws_rcv.next().await {
         let msg = match result {
@@ -47,43 +69,104 @@ pub async fn client_connection(ws: WebSocket, id: String, clients: Clients, mut
         };
 
         // Run a function when a message is received
-        client_msg(&id, msg, &clients, redis_client.clone(), &receiver_manager).await;
+        let verdict = client_msg(&id, user_id, ip, msg, &clients, redis_client.clone(), &receiver_manager, &limiter).await;
+
+        // Close the connection of clients which keep going over their limits
+        if verdict == Verdict::Disconnect {
+            println!("{} disconnected for going over its rate limits", id);
+            send_to(&clients, &id, Message::close_with(1008u16, "rate limited")).await;
+            break;
+        }
     }
 
-    // Delete client when they disconnect
+    // Let receivers clean up, then delete client when they disconnect
+    receiver_manager.client_disconnected(&id, &clients, redis_client.clone()).await;
     clients.write().await.remove(&id);
+    limiter.forget(&id).await;
     println!("{} disconnected", id)
 }
 
 // Handle a message from a client
-async fn client_msg(id: &str, msg: Message, clients: &Clients, redis_client: redis::Client, receiver_manager: &Receivers) {
+#[allow(clippy::too_many_arguments)]
+async fn client_msg(id: &str, user_id: usize, ip: Option<IpAddr>, msg: Message, clients: &Clients, redis_client: redis::Client, receiver_manager: &Receivers, limiter: &Limiter) -> Verdict {
+    // Time sync reports when the frame arrived, so take the time before doing anything else
+    let received_at = unix_millis();
     println!("received message from {}: {:?}", id, msg);
 
     // Convert message to a reference
     let message = match msg.to_str() {
         Ok(v) => v,
-        Err(_) => return,
+        Err(_) => return Verdict::Allowed,
+    };
+
+    // Split message at equals to determine the message type
+    let frame = validate::parse_frame(message);
+    let receiver_id = match frame {
+        Frame::Ping => "ping",
+        Frame::Request { receiver_id, .. } if receiver_manager.receivers.contains_key(receiver_id) => receiver_id,
+        // Malformed frames and unknown receivers share the default bucket, made up ids must not get fresh ones
+        Frame::Request { .. } | Frame::Invalid(_) => "*",
     };
 
-    // Check if client is just pinging
-    if message == "ping" || message == "ping\n" {
-        return;
+    // Throttle the client before doing any work for it, pings are limited like any other frame
+    let verdict = limiter.check_frame(receiver_id, id, user_id, ip).await;
+    match verdict {
+        Verdict::Allowed => {}
+        Verdict::Limited => {
+            send_error(clients, id, "rate_limited", "too many messages, slow down").await;
+            return verdict;
+        }
+        Verdict::Disconnect => return verdict,
     }
 
+    let (receiver_id, received) = match frame {
+        Frame::Request { receiver_id, payload } => (receiver_id, payload),
+        // Check if client is just pinging
+        Frame::Ping => return verdict,
+        Frame::Invalid(reason) => {
+            send_error(clients, id, "invalid_frame", reason).await;
+            return verdict;
+        }
+    };
+
     // Print message to console
     println!("{}", message);
 
-    match message.split_once('=') {
-        // Split message at equals to determine the message type
-        Some((receiver_id, received)) => {
-            match receiver_manager.receivers.get(receiver_id) {
-                // Pass message on to the receiver for the message type
-                Some(v) => block_on(v.receive_msg(id, received, clients, redis_client)),
-                None => return,
-            };
-        }
-        None => eprintln!("Expected <id>=<value>")
+    // Pass message on to the receiver for the message type
+    match receiver_manager.receivers.get(receiver_id) {
+        Some(v) => v.receive_timed(id, received, received_at, clients, redis_client).await,
+        None => send_error(clients, id, "unknown_receiver", "no receiver uses that id").await,
     }
+
+    verdict
+}
+
+// Error sent to a client when a request could not be handled
+#[derive(Serialize, Debug)]
+pub struct ErrorFrame<'a> {
+    #[serde(rename = "code")]
+    code: &'a str,
+    #[serde(rename = "message")]
+    message: &'a str,
+}
+
+// Send a message to a single client
+pub async fn send_to(clients: &Clients, id: &str, message: Message) {
+    if let Some(sender) = clients.read().await.get(id).and_then(|c| c.sender.as_ref()) {
+        let _ = sender.send(Ok(message));
+    }
+}
+
+// Send a frame made of a key and a json value to a single client
+pub async fn send_json<T: Serialize>(clients: &Clients, id: &str, key: &str, value: &T) {
+    if let Ok(as_json) = serde_json::to_string(value) {
+        send_to(clients, id, Message::text(key.to_string() + "=" + &as_json)).await;
+    }
+}
+
+// Send an error frame to a single client
+pub async fn send_error(clients: &Clients, id: &str, code: &str, message: &str) {
+    send_json(clients, id, "error", &ErrorFrame { code, message }).await;
 }
 
 // Receiver for adding a listen topic
@@ -91,20 +174,111 @@ pub struct TopicRequestReceiver;
 #[async_trait]
 impl Receiver for TopicRequestReceiver {
     // Handle receiving a message
-    async fn receive_msg(&self, id: &str, msg: &str, clients: &Clients, _redis_client: redis::Client) {
+    async fn receive_msg(&self, id: &str, msg: &str, clients: &Clients, redis_client: redis::Client) {
         // Create a topic from json
-        let topics_req: TopicsRequest = match from_str(&msg) {
+        let topics_req: TopicsRequest = match from_str(msg) {
             Ok(v) => v,
             Err(e) => {
                 eprintln!("error while passing message to topics request: {}", e);
+                send_error(clients, id, "invalid_request", "expected a topics request").await;
+                return;
+            }
+        };
+
+        // Reject topic names which could not have been published to
+        if let Err(reason) = topics_req.check() {
+            send_error(clients, id, "invalid_topic", reason).await;
+            return;
+        }
+
+        // Determine which part of the history should be replayed
+        let replay = match (topics_req.last, topics_req.since) {
+            (_, Some(since)) => Some(Replay::Since(since)),
+            (Some(last), None) => Some(Replay::Last(last)),
+            (None, None) => None,
+        };
+
+        // Listen to the new topics, live messages are held back until the history was sent
+        let (user_id, buffer, replay) = {
+            let mut locked = clients.write().await;
+            let v = match locked.get_mut(id) {
+                Some(v) => v,
+                None => return,
+            };
+
+            // Add topic to client
+            v.topics = topics_req.topics.clone();
+            let replay = match replay {
+                Some(r) if v.sender.is_some() => r,
+                _ => return,
+            };
+            v.replay.start();
+
+            (v.user_id, v.replay.clone(), replay)
+        };
+
+        // Load the history without holding the lock so publishers are not blocked by redis
+        let entries = load_history(user_id, &topics_req.topics, &replay, redis_client).await;
+
+        // Publishers hold a read lock while sending, so nothing is sent live while the history and held back messages go out
+        let locked = clients.write().await;
+        let sender = match locked.get(id).and_then(|c| c.sender.as_ref()) {
+            Some(v) => v,
+            None => {
+                buffer.finish();
                 return;
             }
         };
 
-        // Add topic to client
-        let mut locked = clients.write().await;
-        if let Some(v) = locked.get_mut(id) {
-            v.topics = topics_req.topics;
+        // Remember the newest replayed message of each topic
+        let mut replayed: HashMap<&str, &str> = HashMap::new();
+        for (topic, stream_id, message) in &entries {
+            replayed.insert(topic, stream_id);
+            let _ = sender.send(Ok(message.clone()));
+        }
+
+        // Send messages published during the replay which were not part of the history
+        for live in buffer.finish() {
+            let in_history = match (live.stream_id.as_deref(), replayed.get(live.topic.as_str())) {
+                (Some(stream_id), Some(last)) => !history::is_after(stream_id, last),
+                _ => false,
+            };
+            if !in_history {
+                let _ = sender.send(Ok(live.message));
+            }
         }
     }
-}
\ No newline at end of file
+}
+
+// Load the requested history of each topic, returning the topic, stream id and frame of every message
+async fn load_history(user_id: usize, topics: &[String], replay: &Replay, redis_client: redis::Client) -> Vec<(String, String, Message)> {
+    // Establish connection to redis
+    let mut redis_con = match get_con(redis_client).await {
+        Ok(v) => v,
+        Err(_) => {
+            eprintln!("could not connect to redis");
+            return Vec::new();
+        }
+    };
+
+    let mut messages = Vec::new();
+    for topic in topics {
+        let entries = match history::load(&mut redis_con, topic, replay).await {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("could not load history of topic {}: {}", topic, e);
+                continue;
+            }
+        };
+
+        // Only send messages which were addressed to this user
+        for (stream_id, record) in entries.into_iter()
+            .filter(|(_, record)| record.user_id.is_none_or(|u| u == user_id)) {
+            if let Some(message) = record.message(&stream_id, topic) {
+                messages.push((topic.clone(), stream_id, message));
+            }
+        }
+    }
+
+    messages
+}
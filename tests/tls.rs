@@ -0,0 +1,189 @@
+// Runs the server with a self-signed certificate and talks to it over TLS
+// Redis is not needed, only routes which work without it are used
+
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use rustls::{ClientConfig, RootCertStore};
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, ServerName};
+use sha2::Sha256;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+// Secret the test signs user tokens with
+const TOKEN_SECRET: &str = "test-secret";
+
+// Server process which is killed when the test ends
+struct Server {
+    child: Child,
+    port: u16,
+    dir: PathBuf,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+// Create a self-signed certificate for localhost, returning it as PEM and DER
+fn self_signed() -> (String, String, CertificateDer<'static>) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).expect("can generate certificate");
+    (certified.cert.pem(), certified.key_pair.serialize_pem(), certified.cert.der().clone())
+}
+
+fn write_cert(dir: &Path, cert_pem: &str, key_pem: &str) {
+    std::fs::write(dir.join("cert.pem"), cert_pem).expect("can write certificate");
+    std::fs::write(dir.join("key.pem"), key_pem).expect("can write key");
+}
+
+// Sign a user token the way the backend does
+fn user_token(user_id: usize, username: &str) -> String {
+    let expires_at = SystemTime::now().duration_since(UNIX_EPOCH).expect("clock is after 1970").as_secs() + 60;
+    let claims = URL_SAFE_NO_PAD.encode(format!(r#"{{"userId":{},"username":"{}","exp":{}}}"#, user_id, username, expires_at));
+    let mut mac = Hmac::<Sha256>::new_from_slice(TOKEN_SECRET.as_bytes()).expect("hmac takes any key");
+    mac.update(claims.as_bytes());
+    format!("{}.{}", claims, URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()))
+}
+
+// Start the server on a free port with the given certificate
+fn start(cert_pem: &str, key_pem: &str) -> Server {
+    let port = TcpListener::bind("127.0.0.1:0").and_then(|l| l.local_addr()).expect("can find a free port").port();
+    let dir = std::env::temp_dir().join(format!("vradio-tls-{}-{}", std::process::id(), port));
+    std::fs::create_dir_all(&dir).expect("can create certificate directory");
+    write_cert(&dir, cert_pem, key_pem);
+
+    let child = Command::new(env!("CARGO_BIN_EXE_vradio-ws"))
+        .env("VRADIO_LISTEN_ADDR", format!("127.0.0.1:{}", port))
+        .env("VRADIO_PUBLIC_HOST", format!("localhost:{}", port))
+        .env("VRADIO_TLS_CERT", dir.join("cert.pem"))
+        .env("VRADIO_TLS_KEY", dir.join("key.pem"))
+        .env("VRADIO_TLS_RELOAD_SECS", "1")
+        .env("VRADIO_USER_TOKEN_SECRET", TOKEN_SECRET)
+        .spawn()
+        .expect("can start server");
+
+    Server { child, port, dir }
+}
+
+// Open a TLS connection trusting only the given certificate
+async fn connect(port: u16, trusted: &CertificateDer<'static>) -> std::io::Result<TlsStream<TcpStream>> {
+    let mut roots = RootCertStore::empty();
+    roots.add(trusted.clone()).expect("can trust certificate");
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    let name = ServerName::try_from("localhost").expect("localhost is a valid name");
+    TlsConnector::from(Arc::new(config)).connect(name, stream).await
+}
+
+// Wait until the server accepts connections with a certificate
+async fn connect_retrying(port: u16, trusted: &CertificateDer<'static>) -> TlsStream<TcpStream> {
+    for _ in 0..100 {
+        if let Ok(stream) = connect(port, trusted).await {
+            return stream;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    panic!("server did not accept the certificate in time");
+}
+
+// Send an HTTP/1.1 request on a kept alive connection, returning the status and body
+async fn request(stream: &mut TlsStream<TcpStream>, method: &str, path: &str, body: &str) -> (u16, String) {
+    request_as(stream, None, method, path, body).await
+}
+
+// Send a request, optionally with a user token
+async fn request_as(stream: &mut TlsStream<TcpStream>, token: Option<&str>, method: &str, path: &str, body: &str) -> (u16, String) {
+    let authorization = token.map(|t| format!("Authorization: Bearer {}\r\n", t)).unwrap_or_default();
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        method, path, authorization, body.len(), body
+    );
+    stream.write_all(request.as_bytes()).await.expect("can send request");
+
+    // Read until the headers are complete, then read the rest of the body
+    let mut response = Vec::new();
+    let mut buf = [0u8; 4096];
+    let header_end = loop {
+        let read = stream.read(&mut buf).await.expect("can read response");
+        assert!(read > 0, "connection closed before the response");
+        response.extend_from_slice(&buf[..read]);
+        if let Some(i) = response.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&response[..header_end]).to_string();
+    let status = head.split(' ').nth(1).and_then(|s| s.parse().ok()).expect("response has a status");
+    let length: usize = head.lines()
+        .find_map(|l| l.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+
+    while response.len() < header_end + length {
+        let read = stream.read(&mut buf).await.expect("can read body");
+        assert!(read > 0, "connection closed before the body");
+        response.extend_from_slice(&buf[..read]);
+    }
+
+    (status, String::from_utf8_lossy(&response[header_end..header_end + length]).to_string())
+}
+
+#[tokio::test]
+async fn serves_tls_and_hands_out_wss_urls() {
+    let (cert_pem, key_pem, cert_der) = self_signed();
+    let server = start(&cert_pem, &key_pem);
+
+    let mut stream = connect_retrying(server.port, &cert_der).await;
+    let (status, _) = request(&mut stream, "GET", "/health", "").await;
+    assert_eq!(status, 200);
+
+    // Registering needs a token from the backend
+    let (status, _) = request(&mut stream, "POST", "/register", r#"{"user_id":1}"#).await;
+    assert_eq!(status, 401);
+
+    let token = user_token(1, "listener");
+    let (status, body) = request_as(&mut stream, Some(&token), "POST", "/register", "{}").await;
+    assert_eq!(status, 200);
+    assert!(body.contains(&format!("wss://localhost:{}/ws/", server.port)), "unexpected register response {}", body);
+}
+
+#[tokio::test]
+async fn reloads_certificate_without_dropping_connections() {
+    let (old_cert, old_key, old_der) = self_signed();
+    let server = start(&old_cert, &old_key);
+
+    // Open a connection with the old certificate before rotating it
+    let mut old_stream = connect_retrying(server.port, &old_der).await;
+    let (status, _) = request(&mut old_stream, "GET", "/health", "").await;
+    assert_eq!(status, 200);
+
+    let (new_cert, new_key, new_der) = self_signed();
+    write_cert(&server.dir, &new_cert, &new_key);
+
+    // New connections get the new certificate once the server notices the change
+    let mut new_stream = connect_retrying(server.port, &new_der).await;
+    let (status, _) = request(&mut new_stream, "GET", "/health", "").await;
+    assert_eq!(status, 200);
+    assert!(connect(server.port, &old_der).await.is_err(), "old certificate is still served");
+
+    // The connection opened before the rotation keeps working
+    let (status, _) = request(&mut old_stream, "GET", "/health", "").await;
+    assert_eq!(status, 200);
+}